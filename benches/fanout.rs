@@ -0,0 +1,84 @@
+//! Fan-out of a broadcast to the members of a group:
+//! `cargo bench --bench fanout`
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chaline_websocket::bench::{send_to_local_group, ClientRoom, ServerMap, ServerRoom};
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{protocol::Message, Bytes};
+
+const CLIENTS: u64 = 10_000;
+const PAYLOAD: usize = 4096;
+
+fn fanout(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let smap = ServerMap::default();
+    let mut receivers = vec![];
+    let mut clients = vec![];
+    for global_id in 0..CLIENTS {
+        let (c, rx) = mpsc::unbounded_channel();
+        clients.push(ClientRoom { c, global_id });
+        receivers.push(rx);
+    }
+    rt.block_on(async {
+        smap.lock("room/group").await.insert(
+            "room/group".to_string(),
+            ServerRoom {
+                clients: Arc::new(clients),
+            },
+        );
+    });
+    let payload = Bytes::from(vec![b'x'; PAYLOAD]);
+    // emptied after every round, out of the measured time
+    let receivers = Mutex::new(receivers);
+    let drain = || {
+        receivers
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .for_each(|rx| while rx.try_recv().is_ok() {})
+    };
+
+    let (smap, payload, drain) = (&smap, &payload, &drain);
+
+    let mut group = c.benchmark_group("fanout to 10k members");
+    group.sample_size(20);
+    group.bench_function("shared", |b| {
+        b.to_async(&rt).iter_custom(|rounds| async move {
+            let mut total = Duration::ZERO;
+            for _ in 0..rounds {
+                let start = Instant::now();
+                send_to_local_group(smap, "room/group", Message::Binary(payload.clone())).await;
+                total += start.elapsed();
+                drain();
+            }
+            total
+        });
+    });
+    // former behavior: the member list and the payload copied for every broadcast
+    group.bench_function("copied", |b| {
+        b.to_async(&rt).iter_custom(|rounds| async move {
+            let mut total = Duration::ZERO;
+            for _ in 0..rounds {
+                let start = Instant::now();
+                let members = smap.lock("room/group").await["room/group"].clients.to_vec();
+                for client in members {
+                    let _ = client
+                        .c
+                        .send(Message::Binary(Bytes::copy_from_slice(payload)));
+                }
+                total += start.elapsed();
+                drain();
+            }
+            total
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+use std::convert::Infallible;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use crate::{
+    auth,
+    com::{close_group, str_to_roomgroup, ServerState, GROUP_CLOSED_MESSAGE},
+    config_loader::{AdminConfig, WebhookEvent},
+};
+
+const MAX_BODY_SIZE: usize = 4096;
+
+/// HTTP API for the backends:
+/// - `POST /groups/<room>/<group>/close`: the body (optional) is sent to the members
+///   before they are disconnected
+pub async fn serve_admin(conf: AdminConfig, state: ServerState) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&conf.address).await?;
+    println!("Admin API listening on http://{}", conf.address);
+
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        let conf = conf.clone();
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(|req| handle_request(req, &conf, &state));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                error!("Admin API connection failed: {}", e);
+            }
+        });
+    }
+}
+
+fn response(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body.to_string())));
+    *resp.status_mut() = status;
+    resp
+}
+
+async fn handle_request(
+    req: Request<Incoming>,
+    conf: &AdminConfig,
+    state: &ServerState,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| auth::token_eq(&conf.token, token));
+    if !authorized {
+        warn!("Admin API: unauthorized {} {}", req.method(), req.uri());
+        return Ok(response(StatusCode::UNAUTHORIZED, "unauthorized"));
+    }
+
+    let path = req.uri().path().to_string();
+    let room_group_name = match (req.method(), path.strip_prefix("/groups/")) {
+        (&Method::POST, Some(rest)) => rest.strip_suffix("/close"),
+        _ => None,
+    };
+    let Some(room_group_name) = room_group_name else {
+        return Ok(response(StatusCode::NOT_FOUND, "not found"));
+    };
+
+    let rg = match str_to_roomgroup(&state.room_configs, room_group_name) {
+        Some(rg) if rg.group.is_some() => rg,
+        _ => return Ok(response(StatusCode::NOT_FOUND, "unknown room group")),
+    };
+
+    let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Ok(response(StatusCode::PAYLOAD_TOO_LARGE, "body too large")),
+    };
+    let message = match String::from_utf8(body.to_vec()) {
+        Ok(m) if m.trim().is_empty() => GROUP_CLOSED_MESSAGE.to_string(),
+        Ok(m) => m,
+        Err(_) => return Ok(response(StatusCode::BAD_REQUEST, "body is not utf-8")),
+    };
+
+    // members may be connected to other nodes, the close is always published
+    state
+        .webhooks
+        .notify(WebhookEvent::Destroy, &rg.full_roomgroup, None, None);
+    close_group(state.backplane.as_ref(), &rg.full_roomgroup, message.into()).await;
+    info!("Admin API: {} closed", rg.full_roomgroup);
+    Ok(response(StatusCode::OK, "closed"))
+}
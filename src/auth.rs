@@ -0,0 +1,33 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &str, data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(data);
+    mac
+}
+
+/// Hex encoded HMAC-SHA256 of `data`, checked by a backend sharing `secret`
+pub fn sign(secret: &str, data: &[u8]) -> String {
+    hex::encode(mac(secret, data).finalize().into_bytes())
+}
+
+/// Constant time check of a hex encoded HMAC-SHA256 of `data`, signed by a backend sharing `secret`
+pub fn verify(secret: &str, data: &[u8], signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    mac(secret, data).verify_slice(&signature).is_ok()
+}
+
+/// Constant time comparison of a secret token
+pub fn token_eq(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
@@ -0,0 +1,452 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::{future::BoxFuture, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_tungstenite::tungstenite::{protocol::Message, Bytes};
+use tokio_util::{
+    codec::{FramedRead, LinesCodec},
+    sync::CancellationToken,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    auth,
+    com::{disconnect_local_group, send_to_local_group, ServerMap},
+    config_loader::BackplaneConfig,
+};
+
+const PEER_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// an unauthenticated peer must send its token line in time
+const PEER_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TOKEN_LINE: usize = 1024;
+/// hex encoded payloads of up to 64 MiB (tungstenite default message size)
+const MAX_EVENT_LINE: usize = 2 * (64 << 20) + 1024;
+
+/// Group operation shared by every node of a cluster
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackplaneEvent {
+    Broadcast { group: String, msg: Message },
+    Disconnect { group: String },
+}
+
+/// Delivers group operations to the members connected to this node, and to the other nodes
+pub trait Backplane: Send + Sync {
+    fn publish(&self, event: BackplaneEvent) -> BoxFuture<'_, ()>;
+}
+
+async fn deliver_local(smap: &ServerMap, event: BackplaneEvent) {
+    match event {
+        BackplaneEvent::Broadcast { group, msg } => send_to_local_group(smap, &group, msg).await,
+        BackplaneEvent::Disconnect { group } => disconnect_local_group(smap, &group).await,
+    }
+}
+
+/// Single node, events only reach the local members
+pub struct LocalBackplane {
+    smap: Arc<ServerMap>,
+}
+
+impl LocalBackplane {
+    pub fn new(smap: Arc<ServerMap>) -> Self {
+        LocalBackplane { smap }
+    }
+}
+
+impl Backplane for LocalBackplane {
+    fn publish(&self, event: BackplaneEvent) -> BoxFuture<'_, ()> {
+        Box::pin(deliver_local(&self.smap, event))
+    }
+}
+
+/// Peer mesh: every node sends its events to all the configured peers,
+/// as json lines over TCP, after a line with the shared token
+pub struct TcpBackplane {
+    smap: Arc<ServerMap>,
+    peers: Vec<mpsc::UnboundedSender<Bytes>>,
+}
+
+impl TcpBackplane {
+    pub async fn start(
+        conf: &BackplaneConfig,
+        smap: Arc<ServerMap>,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<Arc<Self>> {
+        let listener = TcpListener::bind(&conf.address).await?;
+        println!("Backplane listening on {}", listener.local_addr()?);
+        tokio::spawn(accept_peers(
+            listener,
+            conf.token.clone(),
+            smap.clone(),
+            shutdown.clone(),
+        ));
+
+        let peers = conf
+            .peers
+            .iter()
+            .map(|peer| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(write_to_peer(
+                    peer.clone(),
+                    conf.token.clone(),
+                    rx,
+                    shutdown.clone(),
+                ));
+                tx
+            })
+            .collect();
+
+        Ok(Arc::new(TcpBackplane { smap, peers }))
+    }
+}
+
+impl Backplane for TcpBackplane {
+    fn publish(&self, event: BackplaneEvent) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(line) = encode_event(&event) {
+                // encoded once, shared by every peer
+                let line = Bytes::from(line + "\n");
+                for peer in &self.peers {
+                    let _ = peer.send(line.clone());
+                }
+            }
+            deliver_local(&self.smap, event).await;
+        })
+    }
+}
+
+fn encode_event(event: &BackplaneEvent) -> Option<String> {
+    let v = match event {
+        BackplaneEvent::Broadcast {
+            group,
+            msg: Message::Text(txt),
+        } => json!({"type": "broadcast", "group": group, "text": txt.as_str()}),
+        BackplaneEvent::Broadcast {
+            group,
+            msg: Message::Binary(data),
+        } => json!({"type": "broadcast", "group": group, "binary": hex::encode(data)}),
+        BackplaneEvent::Broadcast { .. } => return None,
+        BackplaneEvent::Disconnect { group } => json!({"type": "disconnect", "group": group}),
+    };
+    Some(v.to_string())
+}
+
+fn decode_event(line: &str) -> Option<BackplaneEvent> {
+    let v: Value = serde_json::from_str(line).ok()?;
+    let group = v.get("group")?.as_str()?.to_string();
+
+    match v.get("type")?.as_str()? {
+        "broadcast" => {
+            let msg = if let Some(txt) = v.get("text").and_then(|x| x.as_str()) {
+                Message::text(txt)
+            } else {
+                let data = hex::decode(v.get("binary")?.as_str()?).ok()?;
+                Message::Binary(Bytes::from(data))
+            };
+            Some(BackplaneEvent::Broadcast { group, msg })
+        }
+        "disconnect" => Some(BackplaneEvent::Disconnect { group }),
+        _ => None,
+    }
+}
+
+async fn accept_peers(
+    listener: TcpListener,
+    token: String,
+    smap: Arc<ServerMap>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => return,
+            },
+            _ = shutdown.cancelled() => return,
+        };
+        let token = token.clone();
+        let smap = smap.clone();
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            // lines are bounded, a long token line is refused without being buffered
+            let mut lines =
+                FramedRead::new(stream, LinesCodec::new_with_max_length(MAX_TOKEN_LINE));
+            match tokio::time::timeout(PEER_AUTH_TIMEOUT, lines.next()).await {
+                Ok(Some(Ok(line))) if auth::token_eq(&token, &line) => {
+                    info!("Backplane peer {} connected", addr)
+                }
+                Err(_) => {
+                    warn!("Backplane peer {} refused: no token in time", addr);
+                    return;
+                }
+                _ => {
+                    warn!("Backplane peer {} refused: invalid token", addr);
+                    return;
+                }
+            }
+            *lines.decoder_mut() = LinesCodec::new_with_max_length(MAX_EVENT_LINE);
+
+            // events from peers are only delivered locally
+            loop {
+                let line = tokio::select! {
+                    line = lines.next() => line,
+                    _ = shutdown.cancelled() => break,
+                };
+                match line {
+                    Some(Ok(line)) => match decode_event(&line) {
+                        Some(event) => deliver_local(&smap, event).await,
+                        None => warn!("Backplane peer {}: invalid event {}", addr, line),
+                    },
+                    Some(Err(e)) => {
+                        warn!("Backplane peer {} dropped: {}", addr, e);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            info!("Backplane peer {} disconnected", addr);
+        });
+    }
+}
+
+/// Wait before reconnecting a peer, false when the server stops meanwhile
+async fn reconnect_delay(
+    rx: &mut mpsc::UnboundedReceiver<Bytes>,
+    shutdown: &CancellationToken,
+) -> bool {
+    // events are realtime, don't queue them while the peer is down
+    while rx.try_recv().is_ok() {}
+    if rx.is_closed() {
+        return false;
+    }
+    tokio::select! {
+        _ = tokio::time::sleep(PEER_RECONNECT_DELAY) => true,
+        _ = shutdown.cancelled() => false,
+    }
+}
+
+async fn write_to_peer(
+    peer: String,
+    token: String,
+    mut rx: mpsc::UnboundedReceiver<Bytes>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let mut stream = match TcpStream::connect(&peer).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Backplane peer {} unreachable: {}", peer, e);
+                if !reconnect_delay(&mut rx, &shutdown).await {
+                    return;
+                }
+                continue;
+            }
+        };
+        if let Err(e) = stream.write_all(format!("{}\n", token).as_bytes()).await {
+            warn!("Backplane peer {} refused the token: {}", peer, e);
+            if !reconnect_delay(&mut rx, &shutdown).await {
+                return;
+            }
+            continue;
+        }
+
+        loop {
+            let line = tokio::select! {
+                line = rx.recv() => line,
+                _ = shutdown.cancelled() => return,
+            };
+            let Some(line) = line else {
+                return;
+            };
+            if let Err(e) = stream.write_all(&line).await {
+                error!("Backplane peer {} lost: {}", peer, e);
+                break;
+            }
+        }
+        if !reconnect_delay(&mut rx, &shutdown).await {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::com::{ClientRoom, ServerRoom};
+    use tokio::io::AsyncReadExt;
+
+    fn free_address() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn node_conf(address: &str, peer: &str) -> BackplaneConfig {
+        BackplaneConfig {
+            address: address.to_string(),
+            peers: vec![peer.to_string()],
+            token: "secret".to_string(),
+        }
+    }
+
+    #[test]
+    fn events_round_trip() {
+        for event in [
+            BackplaneEvent::Broadcast {
+                group: "room/group".to_string(),
+                msg: Message::text("hello"),
+            },
+            BackplaneEvent::Broadcast {
+                group: "room/group".to_string(),
+                msg: Message::Binary(Bytes::from_static(&[0, 1, 255])),
+            },
+            BackplaneEvent::Disconnect {
+                group: "room/group".to_string(),
+            },
+        ] {
+            let line = encode_event(&event).unwrap();
+            assert_eq!(decode_event(&line), Some(event));
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_reaches_the_members_of_a_peer() {
+        let (address_a, address_b) = (free_address(), free_address());
+        let smap_a = Arc::<ServerMap>::default();
+        let smap_b = Arc::<ServerMap>::default();
+        let node_a = TcpBackplane::start(
+            &node_conf(&address_a, &address_b),
+            smap_a,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        let _node_b = TcpBackplane::start(
+            &node_conf(&address_b, &address_a),
+            smap_b.clone(),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        // a member of the group connected to node B only
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        smap_b.lock("room/group").await.insert(
+            "room/group".to_string(),
+            ServerRoom {
+                clients: Arc::new(vec![ClientRoom {
+                    c: tx,
+                    global_id: 1,
+                }]),
+            },
+        );
+
+        // the peer connection is established asynchronously
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                node_a
+                    .publish(BackplaneEvent::Broadcast {
+                        group: "room/group".to_string(),
+                        msg: Message::text("hello"),
+                    })
+                    .await;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if let Ok(msg) = rx.try_recv() {
+                    return msg;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, Message::text("hello"));
+    }
+
+    #[tokio::test]
+    async fn peers_with_a_wrong_token_are_refused() {
+        let address = free_address();
+        let smap = Arc::<ServerMap>::default();
+        let _node = TcpBackplane::start(
+            &node_conf(&address, "127.0.0.1:1"),
+            smap.clone(),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        smap.lock("room/group").await.insert(
+            "room/group".to_string(),
+            ServerRoom {
+                clients: Arc::new(vec![ClientRoom {
+                    c: tx,
+                    global_id: 1,
+                }]),
+            },
+        );
+
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        let event = encode_event(&BackplaneEvent::Disconnect {
+            group: "room/group".to_string(),
+        })
+        .unwrap();
+        stream
+            .write_all(format!("wrong\n{}\n", event).as_bytes())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn long_token_lines_are_refused() {
+        let address = free_address();
+        let _node = TcpBackplane::start(
+            &node_conf(&address, "127.0.0.1:1"),
+            Arc::default(),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        let _ = stream.write_all(&[b'a'; 4 * MAX_TOKEN_LINE]).await;
+        // closed by the node without waiting for the end of the line
+        let mut buf = [0; 16];
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_stop_with_the_server() {
+        let shutdown = CancellationToken::new();
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_to_peer(
+            "127.0.0.1:1".to_string(),
+            "secret".to_string(),
+            rx,
+            shutdown.clone(),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        shutdown.cancel();
+        assert!(tokio::time::timeout(Duration::from_secs(1), writer)
+            .await
+            .is_ok());
+
+        // or once nothing can publish anymore
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_to_peer(
+            "127.0.0.1:1".to_string(),
+            "secret".to_string(),
+            rx,
+            CancellationToken::new(),
+        ));
+        drop(tx);
+        assert!(tokio::time::timeout(Duration::from_secs(1), writer)
+            .await
+            .is_ok());
+    }
+}
@@ -1,12 +1,25 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{mpsc, OnceCell};
 use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::config_loader::{self, RoomConfig, RoomKind};
+use crate::backplane::{Backplane, BackplaneEvent, LocalBackplane};
+use crate::config_loader::{GlobalConfig, RoomConfig, RoomKind};
+use crate::handshake::HandshakeInfo;
+use crate::hooks::Hooks;
+use crate::limits::ConnectionLimiter;
+use crate::offline::OfflineStore;
+use crate::room_handler::RoomHandler;
+use crate::sharded::ShardedMap;
+use crate::webhook::Webhooks;
 use tracing::{info, warn};
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct RoomGroup {
     pub full_roomgroup: String,
     pub room: String,
@@ -32,7 +45,7 @@ pub fn str_to_roomgroup(confs: &HashMap<String, RoomConfig>, name: &str) -> Opti
 
     if name_parts.len() == 1 {
         match confs.get(name) {
-            Some(conf) if conf.kind == RoomKind::Broadcast => {
+            Some(conf) if matches!(conf.kind, RoomKind::Broadcast | RoomKind::Custom(_)) => {
                 return Some(RoomGroup {
                     full_roomgroup: name.to_string(),
                     room: name.to_string(),
@@ -77,6 +90,13 @@ pub fn str_to_roomgroup(confs: &HashMap<String, RoomConfig>, name: &str) -> Opti
             group: Some(group.to_string()),
             fetch_url: Some(url),
         }),
+        // custom rooms accept both forms
+        RoomKind::Custom(_) => Some(RoomGroup {
+            full_roomgroup: format!("{}/{}", room, group),
+            room: room.to_string(),
+            group: Some(group.to_string()),
+            fetch_url: None,
+        }),
     }
 }
 
@@ -87,142 +107,545 @@ pub struct ClientRoom {
     // pub prefix: String,
 }
 
+/// What is known about a connection once its handshakes are done
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// subject of a verified client certificate granted publisher rights
+    pub trusted_publisher: Option<String>,
+    pub handshake: HandshakeInfo,
+}
+
 #[derive(Clone)]
 pub struct ServerRoom {
-    pub clients: Vec<ClientRoom>,
+    /// copy on write: a broadcast snapshots the members without copying them
+    pub clients: Arc<Vec<ClientRoom>>,
     // pub config: RoomConfig,
 }
 
-pub type ServerMap = HashMap<String, ServerRoom>;
-pub type ClientMap = HashMap<u64, Vec<String>>;
-pub type SharedM<T> = Arc<Mutex<T>>;
+pub type ServerMap = ShardedMap<String, ServerRoom>;
+pub type ClientMap = ShardedMap<u64, Vec<String>>;
+/// checks of the groups being created, shared by their simultaneous joins
+pub type PendingMap = ShardedMap<String, Arc<OnceCell<bool>>>;
+
+/// State shared by every listener of a server
+#[derive(Clone)]
+pub struct ServerState {
+    pub config: Arc<GlobalConfig>,
+    /// room configurations by prefix
+    pub room_configs: Arc<HashMap<String, RoomConfig>>,
+    /// behavior of each room, by prefix
+    pub room_handlers: Arc<HashMap<String, Arc<dyn RoomHandler>>>,
+    pub clients: Arc<ClientMap>,
+    pub rooms: Arc<ServerMap>,
+    pub pending: Arc<PendingMap>,
+    pub offline: Arc<OfflineStore>,
+    pub limiter: Arc<ConnectionLimiter>,
+    pub backplane: Arc<dyn Backplane>,
+    pub hooks: Arc<Hooks>,
+    pub webhooks: Arc<Webhooks>,
+    /// cancelled to stop the listeners and close the connections
+    pub shutdown: CancellationToken,
+    /// tasks of the listeners and of their connections
+    pub tasks: TaskTracker,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        let rooms = Arc::<ServerMap>::default();
+        ServerState {
+            config: Arc::default(),
+            room_configs: Arc::default(),
+            room_handlers: Arc::default(),
+            clients: Arc::default(),
+            backplane: Arc::new(LocalBackplane::new(Arc::clone(&rooms))),
+            rooms,
+            pending: Arc::default(),
+            offline: Arc::default(),
+            limiter: Arc::default(),
+            hooks: Arc::default(),
+            webhooks: Arc::default(),
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+        }
+    }
+}
+
+const FETCH_ATTEMPTS: u32 = 3;
+const FETCH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// a backend not answering fails the check instead of holding the joins waiting for it
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// connections to the backends are reused by every check
+static FETCH_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+});
 
 pub async fn does_room_group_exists(url: &str, group: &str) -> Result<bool, reqwest::Error> {
+    fetch_group(&FETCH_CLIENT, url, group).await
+}
+
+async fn fetch_group(
+    client: &reqwest::Client,
+    url: &str,
+    group: &str,
+) -> Result<bool, reqwest::Error> {
     let full_url = format!("{url}{group}");
-    let resp = reqwest::get(&full_url).await?;
-    if resp.status().is_success() {
-        let text = resp.text().await?;
-        Ok(text.trim().contains("yes"))
-    } else {
-        Ok(false)
+    let resp = client.get(&full_url).send().await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+    // other error statuses (a 503 while the backend restarts) do not mean the group is gone
+    let text = resp.error_for_status()?.text().await?;
+    Ok(text.trim().contains("yes"))
+}
+
+/// Retries on errors, with backoff, before reporting the backend as unreachable
+pub async fn does_room_group_exists_retry(url: &str, group: &str) -> Result<bool, reqwest::Error> {
+    let mut delay = FETCH_RETRY_DELAY;
+    for _ in 1..FETCH_ATTEMPTS {
+        match does_room_group_exists(url, group).await {
+            Ok(v) => return Ok(v),
+            Err(e) => warn!("Checking {}{} failed, retrying: {:?}", url, group, e),
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
     }
+    does_room_group_exists(url, group).await
 }
 
+/// Memberships are recorded in both maps: the members of each room group (ServerMap)
+/// and the room groups of each client (ClientMap).
+/// Nothing is recorded when the group is not valid.
 pub async fn add_client_to_rg(
-    smap: &SharedM<ServerMap>,
-    cmap: &SharedM<ClientMap>,
-    // confs: &HashMap<String, RoomConfig>,
-    conf: RoomConfig,
+    smap: &ServerMap,
+    cmap: &ClientMap,
+    pending: &PendingMap,
+    handler: &dyn RoomHandler,
     rg: RoomGroup,
     client: ClientRoom,
 ) -> bool {
-    {
-        let mut guard = cmap.lock().await;
-        if let Some(cmap_client) = guard.get_mut(&client.global_id) {
-            if cmap_client.contains(&rg.full_roomgroup) {
-                return true;
-            } else {
-                cmap_client.push(rg.full_roomgroup.clone());
-            }
-        } else {
-            guard.insert(client.global_id, vec![]);
-        }
+    if is_member(cmap, client.global_id, &rg.full_roomgroup).await {
+        return true;
     }
-    let mut guard = smap.lock().await;
-    if let Some(rg_name) = guard.get_mut(&rg.full_roomgroup) {
-        rg_name.clients.push(client.clone());
+
+    let joined = {
+        let mut guard = smap.lock(&rg.full_roomgroup).await;
+        guard.get_mut(&rg.full_roomgroup).map(|rg_name| {
+            Arc::make_mut(&mut rg_name.clients).push(client.clone());
+        })
+    };
+    if joined.is_some() {
         info!(
             "New client ({}) added to {}",
             client.global_id, rg.full_roomgroup
         );
-        return true;
-    }
-
-    let is_valid = if conf.kind == config_loader::RoomKind::Broadcast {
-        info!("broadcast room, no need to check group");
-        true
     } else {
-        match (&rg.fetch_url, &rg.group) {
-            (Some(url), Some(group)) => match does_room_group_exists(&url, &group).await {
-                Ok(v) => v,
-                Err(e) => {
-                    warn!(
-                        "Error checking remote group existence for {} ({}): {:?}",
-                        url, group, e
-                    );
-                    false
-                }
-            },
-            _ => false, // this case shouldn't appear
+        // checked without any lock held, simultaneous joins wait for the same check
+        let check = Arc::clone(
+            pending
+                .lock(&rg.full_roomgroup)
+                .await
+                .entry(rg.full_roomgroup.clone())
+                .or_default(),
+        );
+        let is_valid = *check.get_or_init(|| handler.on_group_create(&rg)).await;
+
+        if is_valid {
+            // the first of the simultaneous joins creates the group
+            let mut guard = smap.lock(&rg.full_roomgroup).await;
+            let rg_name = guard
+                .entry(rg.full_roomgroup.clone())
+                .or_insert_with(|| ServerRoom {
+                    clients: Arc::default(),
+                });
+            Arc::make_mut(&mut rg_name.clients).push(client.clone());
         }
-    };
 
-    if is_valid {
-        guard.insert(
-            rg.full_roomgroup.clone(),
-            ServerRoom {
-                clients: vec![client.clone()],
-                // config: conf,
-            },
-        );
+        // the next joins find the group in ServerMap, or check it again
+        {
+            let mut guard = pending.lock(&rg.full_roomgroup).await;
+            if guard
+                .get(&rg.full_roomgroup)
+                .is_some_and(|c| Arc::ptr_eq(c, &check))
+            {
+                guard.remove(&rg.full_roomgroup);
+            }
+        }
+
+        if !is_valid {
+            warn!(
+                "Client ({}) can't be added to {} (invalid group)",
+                client.global_id, &rg.full_roomgroup
+            );
+            return false;
+        }
         info!(
             "Client ({}) added to {} (new group)",
             client.global_id, &rg.full_roomgroup
         );
-        true
-    } else {
-        warn!(
-            "Client ({}) can't be added to {} (invalid group)",
-            client.global_id, &rg.full_roomgroup
-        );
-        false
     }
-    // guard released at end of scope
+
+    cmap.lock(&client.global_id)
+        .await
+        .entry(client.global_id)
+        .or_default()
+        .push(rg.full_roomgroup);
+    true
 }
 
-pub async fn rm_client(smap: &SharedM<ServerMap>, cmap: &SharedM<ClientMap>, id: u64) {
-    {
-        let mut guard = cmap.lock().await;
-        guard.remove(&id);
+pub async fn is_member(cmap: &ClientMap, id: u64, rg: &str) -> bool {
+    cmap.lock(&id)
+        .await
+        .get(&id)
+        .is_some_and(|rgs| rgs.iter().any(|r| r == rg))
+}
+
+/// A client can always send to its room groups, joining a new one is limited
+pub async fn can_join(cmap: &ClientMap, id: u64, rg: &str, max: Option<usize>) -> bool {
+    let Some(max) = max else {
+        return true;
+    };
+    let guard = cmap.lock(&id).await;
+    guard
+        .get(&id)
+        .is_none_or(|rgs| rgs.len() < max || rgs.iter().any(|r| r == rg))
+}
+
+/// Remove the client from all its room groups, returns them
+pub async fn rm_client(smap: &ServerMap, cmap: &ClientMap, id: u64) -> Vec<String> {
+    let rgs = cmap.lock(&id).await.remove(&id).unwrap_or_default();
+    // only the room groups of the client are touched, empty ones are removed
+    for rg in &rgs {
+        let mut guard = smap.lock(rg).await;
+        if let Some(server_room) = guard.get_mut(rg) {
+            Arc::make_mut(&mut server_room.clients).retain(|c| c.global_id != id);
+            if server_room.clients.is_empty() {
+                guard.remove(rg);
+            }
+        }
     }
+    rgs
+}
+
+/// Remove the client from a single room group, returns false if it was not a member
+pub async fn rm_client_from_rg(smap: &ServerMap, cmap: &ClientMap, id: u64, rg: &str) -> bool {
     {
-        let mut guard = smap.lock().await;
-        // for each room, remove clients with `global_id == id`, then remove empty rooms.
-        guard.retain(|_room_name, server_room| {
-            server_room.clients.retain(|c| c.global_id != id);
-            !server_room.clients.is_empty()
-        });
+        let mut guard = cmap.lock(&id).await;
+        if let Some(rgs) = guard.get_mut(&id) {
+            rgs.retain(|r| r != rg);
+        }
+    }
+    let mut guard = smap.lock(rg).await;
+    let Some(server_room) = guard.get_mut(rg) else {
+        return false;
+    };
+    let before = server_room.clients.len();
+    Arc::make_mut(&mut server_room.clients).retain(|c| c.global_id != id);
+    let was_member = server_room.clients.len() != before;
+    if server_room.clients.is_empty() {
+        guard.remove(rg);
     }
+    was_member
 }
 
-pub async fn broadcast_to_group(smap: &SharedM<ServerMap>, group: &str, msg: String) {
-    // hold lock while collecting clients
-    let maybe_roomgroup = {
-        let guard = smap.lock().await;
-        guard.get(group).cloned()
+/// Send to the members of the group connected to this server
+pub async fn send_to_local_group(smap: &ServerMap, group: &str, msg: Message) {
+    // hold lock while taking a snapshot of the members
+    let maybe_members = {
+        let guard = smap.lock(group).await;
+        guard.get(group).map(|room| Arc::clone(&room.clients))
     };
 
-    if let Some(roomgroup) = maybe_roomgroup {
-        // now send without holding the lock
-        for client in roomgroup.clients {
-            // send consumes msg, so clone if necessary
-            let _ = client.c.send(msg.clone().into());
+    if let Some(members) = maybe_members {
+        // now send without holding the lock,
+        // the payload of a message is shared: a clone only bumps its reference count
+        for client in members.iter() {
+            let _ = client.c.send(msg.clone());
         }
     }
 }
 
-pub async fn disconnect_group(smap: &SharedM<ServerMap>, group: &str) {
-    // hold lock while collecting clients
-    let maybe_roomgroup = {
-        let guard = smap.lock().await;
-        guard.get(group).cloned()
-    };
+/// Disconnect the members of the group connected to this server
+pub async fn disconnect_local_group(smap: &ServerMap, group: &str) {
+    send_to_local_group(smap, group, Message::Close(None)).await
+}
+
+pub async fn broadcast_to_group(backplane: &dyn Backplane, group: &str, msg: Message) {
+    backplane
+        .publish(BackplaneEvent::Broadcast {
+            group: group.to_string(),
+            msg,
+        })
+        .await
+}
+
+pub async fn disconnect_group(backplane: &dyn Backplane, group: &str) {
+    backplane
+        .publish(BackplaneEvent::Disconnect {
+            group: group.to_string(),
+        })
+        .await
+}
+
+/// default last message of a group closed by the server
+pub const GROUP_CLOSED_MESSAGE: &str = "group closed";
+
+/// Send a last message to every member, then disconnect them, on every node
+pub async fn close_group(backplane: &dyn Backplane, group: &str, msg: Message) {
+    broadcast_to_group(backplane, group, msg).await;
+    disconnect_group(backplane, group).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_handler::{builtin_handler, GroupRoom};
+    use futures::future::BoxFuture;
+    use proptest::prelude::*;
+    use std::collections::HashSet;
+    use tokio_tungstenite::tungstenite::Bytes;
+
+    #[tokio::test]
+    async fn broadcasts_share_the_members_and_the_payload() {
+        let smap = ServerMap::default();
+        let mut receivers = vec![];
+        let mut clients = vec![];
+        for global_id in 0..3 {
+            let (c, rx) = mpsc::unbounded_channel();
+            clients.push(ClientRoom { c, global_id });
+            receivers.push(rx);
+        }
+        smap.lock("room/group").await.insert(
+            "room/group".to_string(),
+            ServerRoom {
+                clients: Arc::new(clients),
+            },
+        );
+        let payload = Bytes::from(vec![b'x'; 4096]);
+
+        send_to_local_group(&smap, "room/group", Message::Binary(payload.clone())).await;
+        for rx in receivers.iter_mut() {
+            match rx.try_recv() {
+                Ok(Message::Binary(data)) => assert_eq!(data.as_ptr(), payload.as_ptr()),
+                other => panic!("expected the payload, got {:?}", other),
+            }
+        }
+
+        // a snapshot is not changed by a later join, the list is copied on write
+        let snapshot = Arc::clone(&smap.lock("room/group").await["room/group"].clients);
+        let (c, _rx) = mpsc::unbounded_channel();
+        let mut guard = smap.lock("room/group").await;
+        let room = guard.get_mut("room/group").unwrap();
+        Arc::make_mut(&mut room.clients).push(ClientRoom { c, global_id: 3 });
+        assert_eq!((snapshot.len(), room.clients.len()), (3, 4));
+        assert!(!Arc::ptr_eq(&snapshot, &room.clients));
+    }
+
+    /// broadcast rooms are always valid, `game/1` never is
+    const ROOM_GROUPS: [&str; 4] = ["a", "b", "c", "game/1"];
 
-    if let Some(roomgroup) = maybe_roomgroup {
-        // now send without holding the lock
-        for client in roomgroup.clients {
-            // send consumes msg, so clone if necessary
-            let _ = client.c.send(Message::Close(None));
+    fn room_configs() -> HashMap<String, RoomConfig> {
+        let mut confs = HashMap::new();
+        for room in ["a", "b", "c"] {
+            confs.insert(room.to_string(), RoomConfig::new(room, RoomKind::Broadcast));
         }
+        let game = RoomConfig::new("game", RoomKind::Custom("closed".to_string()));
+        confs.insert("game".to_string(), game);
+        confs
+    }
+
+    /// Refuses every group, like a backend answering `no`
+    struct ClosedGroups;
+
+    impl RoomHandler for ClosedGroups {
+        fn on_group_create<'a>(&'a self, _rg: &'a RoomGroup) -> BoxFuture<'a, bool> {
+            Box::pin(async { false })
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Join(u64, usize),
+        Leave(u64, usize),
+        Disconnect(u64),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (0..4u64, 0..ROOM_GROUPS.len()).prop_map(|(id, rg)| Op::Join(id, rg)),
+            1 => (0..4u64, 0..ROOM_GROUPS.len()).prop_map(|(id, rg)| Op::Leave(id, rg)),
+            1 => (0..4u64).prop_map(Op::Disconnect),
+        ]
+    }
+
+    /// Every membership is in both maps, once
+    async fn assert_consistent(smap: &ServerMap, cmap: &ClientMap) {
+        for id in cmap.keys().await {
+            let rgs = cmap.lock(&id).await[&id].clone();
+            assert_eq!(rgs.iter().collect::<HashSet<_>>().len(), rgs.len());
+            for rg in &rgs {
+                let guard = smap.lock(rg).await;
+                assert!(guard[rg].clients.iter().any(|c| c.global_id == id));
+            }
+        }
+        for rg in smap.keys().await {
+            let members = Arc::clone(&smap.lock(&rg).await[&rg].clients);
+            assert!(!members.is_empty());
+            let ids = members.iter().map(|c| c.global_id).collect::<HashSet<_>>();
+            assert_eq!(ids.len(), members.len());
+            for id in ids {
+                let guard = cmap.lock(&id).await;
+                assert!(guard.get(&id).is_some_and(|rgs| rgs.contains(&rg)));
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn memberships_are_consistent(ops in prop::collection::vec(op(), 1..40)) {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async {
+                let confs = room_configs();
+                let handlers = confs
+                    .values()
+                    .map(|conf| {
+                        let handler = builtin_handler(conf, &Arc::default())
+                            .unwrap_or_else(|| Arc::new(ClosedGroups));
+                        (conf.prefix.clone(), handler)
+                    })
+                    .collect::<HashMap<_, _>>();
+                let smap = ServerMap::default();
+                let cmap = ClientMap::default();
+                let pending = PendingMap::default();
+                let mut receivers = vec![];
+                let clients = (0..4u64)
+                    .map(|global_id| {
+                        let (c, rx) = mpsc::unbounded_channel();
+                        receivers.push(rx);
+                        ClientRoom { c, global_id }
+                    })
+                    .collect::<Vec<_>>();
+
+                for op in ops {
+                    match op {
+                        Op::Join(id, rg) => {
+                            let rg = str_to_roomgroup(&confs, ROOM_GROUPS[rg]).unwrap();
+                            let joined = add_client_to_rg(
+                                &smap,
+                                &cmap,
+                                &pending,
+                                handlers[&rg.room].as_ref(),
+                                rg.clone(),
+                                clients[id as usize].clone(),
+                            )
+                            .await;
+                            assert_eq!(joined, rg.group.is_none());
+                        }
+                        Op::Leave(id, rg) => {
+                            rm_client_from_rg(&smap, &cmap, id, ROOM_GROUPS[rg]).await;
+                        }
+                        Op::Disconnect(id) => {
+                            rm_client(&smap, &cmap, id).await;
+                        }
+                    }
+                    assert_consistent(&smap, &cmap).await;
+                }
+
+                // nothing is left behind
+                for id in 0..4u64 {
+                    rm_client(&smap, &cmap, id).await;
+                }
+                assert!(smap.keys().await.is_empty());
+                assert!(cmap.keys().await.is_empty());
+            });
+        }
+    }
+
+    /// fetchURL answering `yes` slowly, returns its url and its hit counter
+    async fn slow_backend() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&hits);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nyes")
+                        .await;
+                });
+            }
+        });
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn simultaneous_joins_share_one_check() {
+        let (url, hits) = slow_backend().await;
+        let mut confs = HashMap::new();
+        confs.insert(
+            "game".to_string(),
+            RoomConfig::new("game", RoomKind::Group(url.clone())),
+        );
+        let handler = GroupRoom { fetch_url: url };
+        let state = ServerState::default();
+        let rg = str_to_roomgroup(&confs, "game/1").unwrap();
+
+        let mut receivers = vec![];
+        let joins = (0..10u64).map(|global_id| {
+            let (c, rx) = mpsc::unbounded_channel();
+            receivers.push(rx);
+            add_client_to_rg(
+                &state.rooms,
+                &state.clients,
+                &state.pending,
+                &handler,
+                rg.clone(),
+                ClientRoom { c, global_id },
+            )
+        });
+        let joined = futures::future::join_all(joins).await;
+
+        assert!(joined.into_iter().all(|j| j));
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(state.rooms.lock("game/1").await["game/1"].clients.len(), 10);
+        assert!(state.pending.keys().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn silent_backends_fail_the_check() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        // the connection is accepted, the request never answered
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+
+        let checked = fetch_group(&client, &url, "1").await;
+        assert!(checked.is_err_and(|e| e.is_timeout()));
     }
 }
@@ -1,5 +1,6 @@
+use regex::Regex;
 use serde_json::Value;
-use std::{collections::HashMap, fmt, fs};
+use std::{collections::HashMap, fmt, fs, path::PathBuf, sync::Arc, time::Duration};
 use tracing::{error, info};
 
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -7,6 +8,8 @@ pub enum RoomKind {
     Broadcast,
     Group(String),
     Individual(String),
+    /// kind registered by an embedding service, see `ServerBuilder::room_kind`
+    Custom(String),
 }
 
 impl fmt::Display for RoomKind {
@@ -15,19 +18,307 @@ impl fmt::Display for RoomKind {
             RoomKind::Broadcast => write!(f, "Broadcast"),
             RoomKind::Group(url) => write!(f, "Group->({})", url),
             RoomKind::Individual(url) => write!(f, "Individual->({})", url),
+            RoomKind::Custom(kind) => write!(f, "Custom({})", kind),
         }
     }
 }
 
+/// Message verb followed by an optional payload (`turn {"player":3}`).
+/// A present payload must pass every defined rule.
+#[derive(Clone, Default)]
+pub struct VerbConfig {
+    pub max_length: Option<usize>,
+    pub regex: Option<Regex>,
+    /// payload must be json matching this schema
+    pub schema: Option<Arc<jsonschema::Validator>>,
+}
+
+/// `map` entry matched with a regex (or a glob, converted to a regex)
+/// whose reply is a template (`$1`, `${room}`...)
+#[derive(Clone)]
+pub struct MessagePattern {
+    pub regex: Regex,
+    pub reply: String,
+}
+
 #[derive(Clone)]
 pub struct RoomConfig {
     pub prefix: String,
     pub kind: RoomKind,
     pub authorized_messages: Vec<String>,
     pub message_map: HashMap<String, String>,
+    /// checked in order, after the exact `message_map`
+    pub message_patterns: Vec<MessagePattern>,
+    pub verbs: HashMap<String, VerbConfig>,
+    /// `Origin` headers accepted for this room, any if empty
+    pub allowed_origins: Vec<String>,
+    /// `Host` headers accepted for this room, any if empty
+    pub allowed_hosts: Vec<String>,
+    /// maximum size of binary frames payload, binary frames are refused if None
+    pub binary_max_size: Option<usize>,
+    /// maximum size of text messages in bytes
+    pub max_message_size: Option<usize>,
+    /// HMAC key of the tokens needed to destroy a group of this room
+    pub destroy_secret: Option<String>,
+    /// without a `destroySecret`, any client can ask for a destruction checked by `fetchURL`
+    pub anonymous_destroy: bool,
+    /// how often live groups are checked against `fetchURL`
+    pub revalidate_interval: Option<Duration>,
+    /// messages kept for the groups of an individual room without any member
+    pub offline: Option<OfflineConfig>,
+    /// backend notified of the events of the room
+    pub webhook: Option<WebhookConfig>,
+}
+
+/// Retention of the offline messages of a room
+#[derive(Clone, Debug)]
+pub struct OfflineConfig {
+    pub dir: PathBuf,
+    /// oldest messages of a group are dropped over this count
+    pub max_messages: usize,
+    pub max_age: Option<Duration>,
+}
+
+/// Event of a room posted to its webhook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookEvent {
+    Join,
+    Leave,
+    Message,
+    Destroy,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 4] = [
+        WebhookEvent::Join,
+        WebhookEvent::Leave,
+        WebhookEvent::Message,
+        WebhookEvent::Destroy,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::Join => "join",
+            WebhookEvent::Leave => "leave",
+            WebhookEvent::Message => "message",
+            WebhookEvent::Destroy => "destroy",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// HMAC key of the signature header
+    pub secret: Option<String>,
+    pub events: Vec<WebhookEvent>,
+}
+
+impl RoomConfig {
+    /// Room without any message rule or limit
+    pub fn new(prefix: &str, kind: RoomKind) -> Self {
+        RoomConfig {
+            prefix: prefix.to_string(),
+            kind,
+            authorized_messages: vec![],
+            message_map: HashMap::new(),
+            message_patterns: vec![],
+            verbs: HashMap::new(),
+            allowed_origins: vec![],
+            allowed_hosts: vec![],
+            binary_max_size: None,
+            max_message_size: None,
+            destroy_secret: None,
+            anonymous_destroy: false,
+            revalidate_interval: None,
+            offline: None,
+            webhook: None,
+        }
+    }
+}
+
+/// binary frames size limit when `"binary": true`
+const DEFAULT_BINARY_MAX_SIZE: usize = 64 * 1024;
+/// offline messages kept by group without `maxMessages`
+const DEFAULT_OFFLINE_MAX_MESSAGES: usize = 100;
+
+/// Array of strings of a json field, empty if missing
+fn string_list(v: &Value, field: &str) -> Vec<String> {
+    v[field]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(|r| r.as_str().map(|s| s.to_string()))
+        .collect::<Vec<String>>()
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    /// CA used to verify optional client certificates (mutual TLS)
+    pub client_ca: Option<String>,
+    /// subjects (CN) of client certificates granted publisher rights, any verified one if empty
+    pub trusted_publishers: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub tls: Option<TlsConfig>,
+}
+
+impl ListenerConfig {
+    pub fn plain(address: &str) -> Self {
+        ListenerConfig {
+            address: address.to_string(),
+            tls: None,
+        }
+    }
+
+    pub fn tls(address: &str, cert: &str, key: &str) -> Self {
+        ListenerConfig {
+            address: address.to_string(),
+            tls: Some(TlsConfig {
+                cert: cert.to_string(),
+                key: key.to_string(),
+                client_ca: None,
+                trusted_publishers: vec![],
+            }),
+        }
+    }
+}
+
+/// HTTP API called by the backends (webhooks)
+#[derive(Clone, Debug)]
+pub struct AdminConfig {
+    pub address: String,
+    /// expected in an `Authorization: Bearer` header
+    pub token: String,
+}
+
+/// TCP mesh of the nodes of a cluster
+#[derive(Clone, Debug)]
+pub struct BackplaneConfig {
+    pub address: String,
+    /// `host:port` of the other nodes
+    pub peers: Vec<String>,
+    /// shared by every node, sent first on each peer connection
+    pub token: String,
+}
+
+#[derive(Clone, Default)]
+pub struct GlobalConfig {
+    pub rooms: Vec<String>,
+    pub listeners: Vec<ListenerConfig>,
+    /// `Origin` headers accepted during the handshake, any if empty
+    pub allowed_origins: Vec<String>,
+    /// `Host` headers accepted during the handshake, any if empty
+    pub allowed_hosts: Vec<String>,
+    /// websocket message and frame limits of every connection (tungstenite default if None)
+    pub max_message_size: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    /// connections refused over these limits
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// room groups a single connection can be a member of
+    pub max_groups_per_client: Option<usize>,
+    /// deadlines of the TLS handshake and of the HTTP upgrade (default if None)
+    pub tls_handshake_timeout: Option<Duration>,
+    pub upgrade_timeout: Option<Duration>,
+    /// deadline of the first message of a connection not subscribed by its path
+    pub first_message_timeout: Option<Duration>,
+    pub admin: Option<AdminConfig>,
+    pub backplane: Option<BackplaneConfig>,
+}
+
+/// Optional size or count field
+fn size_field(v: &Value, field: &str) -> Option<usize> {
+    v.get(field).and_then(|x| x.as_u64()).map(|x| x as usize)
+}
+
+/// Optional duration field in seconds, ignored unless positive
+fn duration_field(v: &Value, field: &str) -> Option<Duration> {
+    let secs = v.get(field)?.as_f64()?;
+    match Duration::try_from_secs_f64(secs) {
+        Ok(d) if !d.is_zero() => Some(d),
+        _ => {
+            error!(
+                "{} must be a positive number of seconds, ignoring {}",
+                field, secs
+            );
+            None
+        }
+    }
+}
+
+fn load_listener_config(v: &Value) -> Option<ListenerConfig> {
+    let address = match v.get("address").and_then(|x| x.as_str()) {
+        Some(a) => a,
+        None => {
+            error!("listener without address field, ignoring it");
+            return None;
+        }
+    };
+
+    match v.get("tls") {
+        None | Some(Value::Null) => Some(ListenerConfig::plain(address)),
+        Some(tls) => {
+            let cert = tls.get("cert").and_then(|x| x.as_str());
+            let key = tls.get("key").and_then(|x| x.as_str());
+            if let (Some(cert), Some(key)) = (cert, key) {
+                let mut conf = ListenerConfig::tls(address, cert, key);
+                if let Some(tls_conf) = conf.tls.as_mut() {
+                    tls_conf.client_ca = tls
+                        .get("clientCA")
+                        .and_then(|x| x.as_str())
+                        .map(|s| s.to_string());
+                    tls_conf.trusted_publishers = string_list(tls, "trustedPublishers");
+                }
+                Some(conf)
+            } else {
+                error!(
+                    "listener {}: tls needs both cert and key fields, ignoring it",
+                    address
+                );
+                None
+            }
+        }
+    }
+}
+
+fn load_admin_config(v: &Value) -> Option<AdminConfig> {
+    let address = v.get("address").and_then(|x| x.as_str());
+    let token = v.get("token").and_then(|x| x.as_str());
+    match (address, token) {
+        (Some(address), Some(token)) if !token.is_empty() => Some(AdminConfig {
+            address: address.to_string(),
+            token: token.to_string(),
+        }),
+        _ => {
+            error!("admin API needs both address and token fields, disabled");
+            None
+        }
+    }
 }
 
-pub fn load_configs() -> Option<Vec<String>> {
+fn load_backplane_config(v: &Value) -> Option<BackplaneConfig> {
+    let address = v.get("address").and_then(|x| x.as_str());
+    let token = v.get("token").and_then(|x| x.as_str());
+    match (address, token) {
+        (Some(address), Some(token)) if !token.is_empty() => Some(BackplaneConfig {
+            address: address.to_string(),
+            peers: string_list(v, "peers"),
+            token: token.to_string(),
+        }),
+        _ => {
+            error!("backplane needs both address and token fields, disabled");
+            None
+        }
+    }
+}
+
+pub fn load_configs() -> Option<GlobalConfig> {
     // ? = return None on error
     let json_data = fs::read_to_string("configs.json").ok()?;
     let v: Value = serde_json::from_str(&json_data).ok()?;
@@ -44,7 +335,147 @@ pub fn load_configs() -> Option<Vec<String>> {
         .filter_map(|r| r.as_str().map(|s| s.to_string()))
         .collect::<Vec<String>>();
 
-    Some(rooms)
+    let listeners = v["listeners"]
+        .as_array()
+        .unwrap_or(&vec![])
+        .iter()
+        .filter_map(load_listener_config)
+        .collect::<Vec<ListenerConfig>>();
+
+    Some(GlobalConfig {
+        rooms,
+        listeners,
+        allowed_origins: string_list(&v, "allowedOrigins"),
+        allowed_hosts: string_list(&v, "allowedHosts"),
+        max_message_size: size_field(&v, "maxMessageSize"),
+        max_frame_size: size_field(&v, "maxFrameSize"),
+        max_connections: size_field(&v, "maxConnections"),
+        max_connections_per_ip: size_field(&v, "maxConnectionsPerIp"),
+        max_groups_per_client: size_field(&v, "maxGroupsPerClient"),
+        tls_handshake_timeout: duration_field(&v, "tlsHandshakeTimeout"),
+        upgrade_timeout: duration_field(&v, "upgradeTimeout"),
+        first_message_timeout: duration_field(&v, "firstMessageTimeout"),
+        admin: v.get("admin").and_then(load_admin_config),
+        backplane: v.get("backplane").and_then(load_backplane_config),
+    })
+}
+
+/// Each wildcard of the glob is a capture group
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str("(.*)"),
+            '?' => re.push_str("(.)"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+fn load_message_pattern(v: &Value) -> Option<MessagePattern> {
+    let reply = match v.get("reply").and_then(|x| x.as_str()) {
+        Some(r) => r.to_string(),
+        None => {
+            error!("pattern without reply field: {}", v);
+            return None;
+        }
+    };
+    let re = match (
+        v.get("regex").and_then(|x| x.as_str()),
+        v.get("glob").and_then(|x| x.as_str()),
+    ) {
+        (Some(re), None) => re.to_string(),
+        (None, Some(glob)) => glob_to_regex(glob),
+        _ => {
+            error!("pattern needs either a regex or a glob field: {}", v);
+            return None;
+        }
+    };
+
+    match Regex::new(&re) {
+        Ok(regex) => Some(MessagePattern { regex, reply }),
+        Err(e) => {
+            error!("invalid pattern {}: {}", re, e);
+            None
+        }
+    }
+}
+
+fn load_verb_config(verb: &str, v: &Value) -> Option<VerbConfig> {
+    let regex = match v.get("regex").and_then(|x| x.as_str()) {
+        Some(r) => match Regex::new(r) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                error!("verb '{}': invalid regex: {}", verb, e);
+                return None;
+            }
+        },
+        None => None,
+    };
+    let schema = match v.get("schema") {
+        Some(schema) => match jsonschema::validator_for(schema) {
+            Ok(validator) => Some(Arc::new(validator)),
+            Err(e) => {
+                error!("verb '{}': invalid json schema: {}", verb, e);
+                return None;
+            }
+        },
+        None => None,
+    };
+
+    Some(VerbConfig {
+        max_length: size_field(v, "maxLength"),
+        regex,
+        schema,
+    })
+}
+
+fn load_offline_config(v: &Value, prefix: &str, kind: &RoomKind) -> Option<OfflineConfig> {
+    if !matches!(kind, RoomKind::Individual(_)) {
+        error!(
+            "{}: offline messages are only kept for individual rooms",
+            prefix
+        );
+        return None;
+    }
+    let Some(dir) = v.get("dir").and_then(|x| x.as_str()) else {
+        error!("{}: offline messages need a dir field", prefix);
+        return None;
+    };
+    Some(OfflineConfig {
+        dir: PathBuf::from(dir),
+        max_messages: size_field(v, "maxMessages").unwrap_or(DEFAULT_OFFLINE_MAX_MESSAGES),
+        max_age: duration_field(v, "maxAge"),
+    })
+}
+
+fn load_webhook_config(v: &Value, prefix: &str, url: &str) -> Option<WebhookConfig> {
+    let events = match v.get("webhookEvents") {
+        Some(_) => {
+            let mut events = vec![];
+            for name in string_list(v, "webhookEvents") {
+                match WebhookEvent::ALL.into_iter().find(|e| e.name() == name) {
+                    Some(event) => events.push(event),
+                    None => {
+                        error!("{}: unknown webhook event '{}'", prefix, name);
+                        return None;
+                    }
+                }
+            }
+            events
+        }
+        None => WebhookEvent::ALL.to_vec(),
+    };
+    Some(WebhookConfig {
+        url: url.to_string(),
+        secret: v
+            .get("webhookSecret")
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string()),
+        events,
+    })
 }
 
 pub fn load_room_config(path: &String) -> Option<RoomConfig> {
@@ -63,12 +494,7 @@ pub fn load_room_config(path: &String) -> Option<RoomConfig> {
     });
     let kind = v.get("type").and_then(|x| x.as_str());
 
-    let auth_msgs = v["authorized"]
-        .as_array()
-        .unwrap_or(&vec![])
-        .iter()
-        .filter_map(|r| r.as_str().map(|s| s.to_string()))
-        .collect::<Vec<String>>();
+    let auth_msgs = string_list(&v, "authorized");
 
     let mut map_msg = HashMap::new();
     if let Some(map_value) = v.get("map") {
@@ -80,46 +506,127 @@ pub fn load_room_config(path: &String) -> Option<RoomConfig> {
         );
     }
 
-    match kind {
-        Some("broadcast") => Some(RoomConfig {
-            prefix: prefix.to_string(),
-            kind: RoomKind::Broadcast,
-            authorized_messages: auth_msgs,
-            message_map: map_msg,
-        }),
+    let mut patterns = vec![];
+    if let Some(patterns_value) = v.get("patterns") {
+        for pattern in patterns_value.as_array()? {
+            patterns.push(load_message_pattern(pattern)?);
+        }
+    }
+
+    let mut verbs = HashMap::new();
+    if let Some(verbs_value) = v.get("verbs") {
+        for (verb, verb_value) in verbs_value.as_object()? {
+            verbs.insert(verb.clone(), load_verb_config(verb, verb_value)?);
+        }
+    }
+
+    let kind = match kind {
+        Some("broadcast") => RoomKind::Broadcast,
         Some("group") => {
             if let Some(url) = v.get("fetchURL").and_then(|x| x.as_str()) {
-                Some(RoomConfig {
-                    prefix: prefix.to_string(),
-                    kind: RoomKind::Group(String::from(url)),
-                    authorized_messages: auth_msgs,
-                    message_map: map_msg,
-                })
+                RoomKind::Group(String::from(url))
             } else {
                 error!("missing fetchURL field necessary for 'group' room type!");
-                None
+                return None;
             }
         }
         Some("individual") => {
             if let Some(url) = v.get("fetchURL").and_then(|x| x.as_str()) {
-                Some(RoomConfig {
-                    prefix: prefix.to_string(),
-                    kind: RoomKind::Individual(String::from(url)),
-                    authorized_messages: auth_msgs,
-                    message_map: map_msg,
-                })
+                RoomKind::Individual(String::from(url))
             } else {
                 error!("missing fetchURL field necessary for 'individual' room type!");
-                None
+                return None;
             }
         }
-        Some(k) => {
-            error!("room type '{}' unknown", k);
-            None
-        }
+        // must be registered when the server starts
+        Some(k) => RoomKind::Custom(k.to_string()),
         None => {
             error!("type field not found, please define it!");
-            None
+            return None;
         }
+    };
+
+    Some(RoomConfig {
+        authorized_messages: auth_msgs,
+        message_map: map_msg,
+        message_patterns: patterns,
+        verbs,
+        allowed_origins: string_list(&v, "allowedOrigins"),
+        allowed_hosts: string_list(&v, "allowedHosts"),
+        binary_max_size: match v.get("binary") {
+            Some(Value::Bool(true)) => Some(DEFAULT_BINARY_MAX_SIZE),
+            Some(b) if b.is_object() => {
+                Some(size_field(b, "maxSize").unwrap_or(DEFAULT_BINARY_MAX_SIZE))
+            }
+            _ => None,
+        },
+        max_message_size: size_field(&v, "maxMessageSize"),
+        destroy_secret: v
+            .get("destroySecret")
+            .and_then(|x| x.as_str())
+            .map(|x| x.to_string()),
+        anonymous_destroy: v
+            .get("anonymousDestroy")
+            .and_then(|x| x.as_bool())
+            .unwrap_or(false),
+        revalidate_interval: duration_field(&v, "revalidateInterval"),
+        offline: match v.get("offline") {
+            Some(o) => Some(load_offline_config(o, prefix, &kind)?),
+            None => None,
+        },
+        webhook: match v.get("webhookURL").and_then(|x| x.as_str()) {
+            Some(url) => Some(load_webhook_config(&v, prefix, url)?),
+            None => None,
+        },
+        ..RoomConfig::new(prefix, kind)
+    })
+}
+
+/// Room configurations of the global configuration, by prefix
+pub fn load_room_configs(conf: &GlobalConfig) -> HashMap<String, RoomConfig> {
+    let mut m = HashMap::new();
+    for e in conf.rooms.iter() {
+        let rc = load_room_config(e).unwrap();
+
+        info!(
+            "> {}\n[{}]={}: {} messages authorized",
+            &e,
+            &rc.prefix,
+            &rc.kind,
+            rc.authorized_messages.len().max(rc.message_map.len()) + rc.verbs.len()
+        );
+
+        if !rc.message_map.is_empty() {
+            info!("{:?}", rc.message_map);
+        }
+        m.insert(rc.prefix.clone(), rc);
+    }
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn durations_must_be_positive() {
+        let v = json!({"ok": 1.5, "zero": 0, "negative": -3, "tiny": 1e-12});
+        assert_eq!(duration_field(&v, "ok"), Some(Duration::from_millis(1500)));
+        assert_eq!(duration_field(&v, "zero"), None);
+        assert_eq!(duration_field(&v, "negative"), None);
+        assert_eq!(duration_field(&v, "tiny"), None);
+        assert_eq!(duration_field(&v, "missing"), None);
+    }
+
+    #[test]
+    fn globs_escape_regex_characters() {
+        assert_eq!(glob_to_regex("ping *"), "^ping (.*)$");
+        let re = Regex::new(&glob_to_regex("v1.? +*")).unwrap();
+        let caps = re.captures("v1.2 +go").unwrap();
+        assert_eq!((&caps[1], &caps[2]), ("2", "go"));
+        assert!(!re.is_match("v102 +go"));
+        assert!(!re.is_match("v1.2 go"));
+        assert!(!re.is_match("xv1.2 +go"));
     }
 }
@@ -1,80 +1,355 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use tracing::warn;
+use regex::Captures;
+use serde_json::Value;
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame, Message},
+    Bytes,
+};
+use tracing::{info, warn};
 
 use crate::{
+    auth,
+    backplane::Backplane,
     com::{
-        disconnect_group, does_room_group_exists, str_to_roomgroup, RoomGroup, ServerMap, SharedM,
-        SplittedMessage,
+        disconnect_group, does_room_group_exists_retry, rm_client_from_rg, str_to_roomgroup,
+        ClientMap, ConnectionInfo, RoomGroup, ServerMap, SplittedMessage,
     },
-    config_loader::RoomConfig,
+    config_loader::{RoomConfig, VerbConfig, WebhookEvent},
+    handshake::room_allows,
+    offline::OfflineStore,
+    webhook::Webhooks,
 };
 
-fn split_message(msg: String, confs: &HashMap<String, RoomConfig>) -> Option<SplittedMessage> {
+fn split_message(
+    msg: String,
+    confs: &HashMap<String, RoomConfig>,
+    default_rg: Option<&RoomGroup>,
+) -> Option<SplittedMessage> {
     let parts = msg.splitn(2, ":").collect::<Vec<_>>();
 
     // println!("split> {:?}", parts);
 
-    if parts.len() < 2 {
-        return None;
-    }
-
     // if !confs.contains_key(parts[0]) {
     //     return None;
     // }
 
-    if let Some(rg) = str_to_roomgroup(confs, parts[0]) {
-        if confs.contains_key(&rg.room) {
-            Some(SplittedMessage {
-                content: parts[1].trim().to_string(),
-                room_group: rg,
-            })
-        } else {
-            warn!("{} room not found", rg.room);
-            None
+    if parts.len() == 2 {
+        if let Some(rg) = str_to_roomgroup(confs, parts[0]) {
+            return if confs.contains_key(&rg.room) {
+                Some(SplittedMessage {
+                    content: parts[1].trim().to_string(),
+                    room_group: rg,
+                })
+            } else {
+                warn!("{} room not found", rg.room);
+                None
+            };
         }
-    } else {
-        None
     }
+
+    // connections routed by their handshake path can leave out the prefix
+    default_rg.map(|rg| SplittedMessage {
+        content: msg.trim().to_string(),
+        room_group: rg.clone(),
+    })
 }
 
 fn is_authorized_message(msg: String, conf: &RoomConfig) -> bool {
-    (conf.authorized_messages.is_empty() && conf.message_map.is_empty())
+    (conf.authorized_messages.is_empty()
+        && conf.message_map.is_empty()
+        && conf.message_patterns.is_empty()
+        && conf.verbs.is_empty())
         || conf.authorized_messages.contains(&msg)
         || conf.message_map.contains_key(&msg)
 }
 
+/// Replace `$1`, `${name}` (captures) and `${room}`, `${group}`, `${sender}`, `${timestamp}`.
+/// Done in one pass, so captured content is never expanded again.
+fn render_template(template: &str, caps: &Captures, rg: &RoomGroup, sender: u64) -> String {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let name = match chars.peek() {
+            Some('{') => {
+                chars.next();
+                chars.by_ref().take_while(|&c| c != '}').collect::<String>()
+            }
+            Some(d) if d.is_ascii_digit() => {
+                let mut name = String::new();
+                while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+                    name.push(d);
+                }
+                name
+            }
+            // `$$` or a lone `$`
+            Some('$') => {
+                chars.next();
+                out.push('$');
+                continue;
+            }
+            _ => {
+                out.push('$');
+                continue;
+            }
+        };
+
+        match name.as_str() {
+            "room" => out.push_str(&rg.room),
+            "group" => out.push_str(rg.group.as_deref().unwrap_or("")),
+            "sender" => out.push_str(&sender.to_string()),
+            "timestamp" => out.push_str(
+                &SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+                    .to_string(),
+            ),
+            n => {
+                let capture = match n.parse::<usize>() {
+                    Ok(i) => caps.get(i),
+                    Err(_) => caps.name(n),
+                };
+                out.push_str(capture.map_or("", |m| m.as_str()));
+            }
+        }
+    }
+
+    out
+}
+
+/// Reply of the first pattern matching the message
+fn match_pattern(content: &str, conf: &RoomConfig, rg: &RoomGroup, sender: u64) -> Option<String> {
+    conf.message_patterns.iter().find_map(|p| {
+        let caps = p.regex.captures(content)?;
+        Some(render_template(&p.reply, &caps, rg, sender))
+    })
+}
+
+/// Split `verb payload`, None if the verb is not declared in the room
+fn split_verb<'a>(
+    content: &'a str,
+    conf: &'a RoomConfig,
+) -> Option<(&'a str, &'a VerbConfig, &'a str)> {
+    let (verb, payload) = content
+        .split_once(char::is_whitespace)
+        .unwrap_or((content, ""));
+    let verb_conf = conf.verbs.get(verb)?;
+    Some((verb, verb_conf, payload.trim()))
+}
+
+/// A missing payload is checked like any other: a verb with a `schema`
+/// (or a regex refusing the empty string) requires one
+fn is_valid_payload(verb: &str, payload: &str, verb_conf: &VerbConfig) -> bool {
+    if let Some(max) = verb_conf.max_length {
+        if payload.len() > max {
+            warn!(
+                "Payload of '{}' too long ({} > {})",
+                verb,
+                payload.len(),
+                max
+            );
+            return false;
+        }
+    }
+    if let Some(re) = &verb_conf.regex {
+        if !re.is_match(payload) {
+            warn!("Payload of '{}' does not match {}", verb, re);
+            return false;
+        }
+    }
+    if let Some(schema) = &verb_conf.schema {
+        match serde_json::from_str::<Value>(payload) {
+            Ok(v) if schema.is_valid(&v) => {}
+            Ok(_) => {
+                warn!("Payload of '{}' does not match its json schema", verb);
+                return false;
+            }
+            Err(e) => {
+                warn!("Payload of '{}' is not valid json: {}", verb, e);
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// Leave one room group, keeping the connection and its other room groups.
+/// Returns the room group left, None if the client was not a member.
+pub async fn handle_group_leave(
+    room_group_name: &str,
+    confs: &HashMap<String, RoomConfig>,
+    smap: &ServerMap,
+    cmap: &ClientMap,
+    client: &ConnectionInfo,
+) -> Result<Option<RoomGroup>, MessageError> {
+    let rg = str_to_roomgroup(confs, room_group_name).ok_or(MessageError::Invalid)?;
+
+    if rm_client_from_rg(smap, cmap, client.id, &rg.full_roomgroup).await {
+        info!("Client ({}) left {}", client.id, rg.full_roomgroup);
+        Ok(Some(rg))
+    } else {
+        info!(
+            "Client ({}) can't leave {}: not a member",
+            client.id, rg.full_roomgroup
+        );
+        Ok(None)
+    }
+}
+
+/// Acknowledge the offline messages of a room group: `room/group id`, the messages
+/// up to `id` are deleted. Returns false if the client is not a member of the room group.
+pub async fn handle_offline_ack(
+    ack: &str,
+    confs: &HashMap<String, RoomConfig>,
+    store: &OfflineStore,
+    client: &ConnectionInfo,
+) -> bool {
+    let Some((room_group_name, id)) = ack.split_once(' ') else {
+        return false;
+    };
+    let (Some(rg), Ok(id)) = (str_to_roomgroup(confs, room_group_name), id.trim().parse()) else {
+        return false;
+    };
+    let Some(offline) = confs.get(&rg.room).and_then(|c| c.offline.as_ref()) else {
+        warn!("{} does not keep offline messages", rg.room);
+        return false;
+    };
+    // only the recipient, subscribed by its path, acknowledges
+    let recipient = client.handshake.room_group.as_ref();
+    if recipient.is_none_or(|path_rg| path_rg.full_roomgroup != rg.full_roomgroup) {
+        warn!(
+            "Client ({}) can't acknowledge messages of {}: not its recipient",
+            client.id, rg.full_roomgroup
+        );
+        return false;
+    }
+
+    store.ack(offline, &rg, id).await;
+    info!(
+        "Client ({}) acknowledged messages of {} up to {}",
+        client.id, rg.full_roomgroup, id
+    );
+    true
+}
+
+/// `<expiry> <signature>`: hex HMAC of `room/group:expiry`, refused after `expiry` (unix seconds)
+fn is_valid_destroy_token(secret: &str, room_group: &str, token: &str) -> bool {
+    let Some((expiry, signature)) = token.split_once(char::is_whitespace) else {
+        return false;
+    };
+    let Ok(expires_at) = expiry.parse::<u64>() else {
+        return false;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if expires_at < now {
+        warn!("Token to destroy {} expired at {}", room_group, expires_at);
+        return false;
+    }
+    let signed = format!("{}:{}", room_group, expiry);
+    auth::verify(secret, signed.as_bytes(), signature.trim())
+}
+
+/// Destroy a group: `room/group [expiry token]`.
+/// Rooms with a `destroySecret` need a token signed by the backend, rooms with
+/// `anonymousDestroy` need the backend to confirm the group does not exist anymore.
+/// Trusted publishers can always destroy a group, other clients can't without either.
 pub async fn handle_group_destruction(
     room_group_name: String,
     confs: &HashMap<String, RoomConfig>,
-    smap: &SharedM<ServerMap>,
+    backplane: &dyn Backplane,
+    webhooks: &Webhooks,
+    client: &ConnectionInfo,
 ) -> bool {
-    // let parts = room_group_name.splitn(2, ":").collect::<Vec<_>>();
+    let destroy = |rg: RoomGroup| async move {
+        webhooks.notify(
+            WebhookEvent::Destroy,
+            &rg.full_roomgroup,
+            Some(client.id),
+            None,
+        );
+        disconnect_group(backplane, &rg.full_roomgroup).await;
+    };
+    let (room_group_name, token) = match room_group_name.split_once(char::is_whitespace) {
+        Some((name, token)) => (name, Some(token.trim())),
+        None => (room_group_name.as_str(), None),
+    };
 
-    // // println!("split> {:?}", parts);
+    if let Some(rg) = str_to_roomgroup(confs, room_group_name) {
+        // trusted publishers don't need the backend to confirm the destruction
+        if let (Some(subject), Some(_)) = (&client.trusted_publisher, &rg.group) {
+            info!(
+                "Trusted publisher '{}' ({}) destroys {}",
+                subject, client.id, rg.full_roomgroup
+            );
+            destroy(rg).await;
+            return true;
+        }
 
-    // if parts.len() < 2 {
-    //     return ;
-    // }
+        let conf = match confs.get(&rg.room) {
+            Some(conf) => conf,
+            None => {
+                warn!("{} room/group not found", rg.room);
+                return false;
+            }
+        };
+
+        if let Some(secret) = &conf.destroy_secret {
+            return match token {
+                Some(token) if is_valid_destroy_token(secret, &rg.full_roomgroup, token) => {
+                    info!(
+                        "Client ({}) destroys {} with a valid token",
+                        client.id, rg.full_roomgroup
+                    );
+                    destroy(rg).await;
+                    true
+                }
+                _ => {
+                    warn!(
+                        "Client ({}) can't destroy {}: missing, invalid or expired token",
+                        client.id, rg.full_roomgroup
+                    );
+                    false
+                }
+            };
+        }
 
-    // // if !confs.contains_key(parts[0]) {
-    // //     return None;
-    // // }
+        if !conf.anonymous_destroy {
+            warn!(
+                "Client ({}) can't destroy {}: no destroySecret nor anonymousDestroy",
+                client.id, rg.full_roomgroup
+            );
+            return false;
+        }
 
-    if let Some(rg) = str_to_roomgroup(confs, &room_group_name) {
-        if confs.contains_key(&rg.room) && rg.group.is_some() && rg.fetch_url.is_some() {
-            match does_room_group_exists(&rg.fetch_url.unwrap(), &rg.group.unwrap()).await {
+        if let (Some(url), Some(group)) = (&rg.fetch_url, &rg.group) {
+            match does_room_group_exists_retry(url, group).await {
                 Ok(v) => {
                     if !v {
-                        disconnect_group(smap, &rg.full_roomgroup).await;
+                        destroy(rg).await;
                         true
                     } else {
                         false
                     }
                 }
-                Err(_) => {
-                    disconnect_group(smap, &rg.full_roomgroup).await;
-                    true
+                // an unreachable backend does not mean the group is gone
+                Err(e) => {
+                    warn!(
+                        "Group {} kept, backend unreachable: {:?}",
+                        rg.full_roomgroup, e
+                    );
+                    false
                 }
             }
         } else {
@@ -86,37 +361,316 @@ pub async fn handle_group_destruction(
     }
 }
 
+/// Why a message is refused, the connection is then closed
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageError {
+    Invalid,
+    TooLarge,
+    TooManyGroups,
+}
+
+impl MessageError {
+    pub fn close_message(&self) -> Message {
+        match self {
+            MessageError::Invalid => Message::Close(None),
+            MessageError::TooLarge => Message::Close(Some(CloseFrame {
+                code: CloseCode::Size,
+                reason: "message too large".into(),
+            })),
+            MessageError::TooManyGroups => Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "too many groups".into(),
+            })),
+        }
+    }
+}
+
 pub struct WebSocketAction {
-    pub send_message: String,
+    pub send_message: Message,
     pub room_group: RoomGroup,
     pub room_config: RoomConfig,
 }
 
-pub fn handle_message(msg: String, confs: &HashMap<String, RoomConfig>) -> Option<WebSocketAction> {
+pub fn handle_message(
+    msg: String,
+    confs: &HashMap<String, RoomConfig>,
+    client: &ConnectionInfo,
+) -> Result<WebSocketAction, MessageError> {
     // if msg.starts_with("-") {
     //     return None;
     // }
 
-    let splitted_msg = split_message(msg, confs)?;
-    let conf = confs.get(&splitted_msg.room_group.room)?;
+    let msg_len = msg.len();
+    let splitted_msg = split_message(msg, confs, client.handshake.room_group.as_ref())
+        .ok_or(MessageError::Invalid)?;
+    let conf = confs
+        .get(&splitted_msg.room_group.room)
+        .ok_or(MessageError::Invalid)?;
 
-    if !is_authorized_message(splitted_msg.content.clone(), conf) {
+    if client.trusted_publisher.is_none() && !room_allows(conf, &client.handshake) {
         warn!(
-            "Unauthorized messaage '{}', authorized {:?}",
-            splitted_msg.content, conf.authorized_messages
+            "Connection {} not allowed in {} (origin {:?}, host {:?})",
+            client.id, conf.prefix, client.handshake.origin, client.handshake.host
         );
-        return None;
+        return Err(MessageError::Invalid);
     }
 
-    let msg_to_send = conf
-        .message_map
-        .get(&splitted_msg.content)
-        .unwrap_or(&splitted_msg.content)
-        .clone();
+    if let Some(max) = conf.max_message_size {
+        if msg_len > max {
+            warn!(
+                "Message of {} bytes refused in {} (max {})",
+                msg_len, conf.prefix, max
+            );
+            return Err(MessageError::TooLarge);
+        }
+    }
+
+    let msg_to_send = match split_verb(&splitted_msg.content, conf) {
+        // declared verbs forward their (valid) payload
+        Some((verb, verb_conf, payload)) => {
+            if client.trusted_publisher.is_none() && !is_valid_payload(verb, payload, verb_conf) {
+                return Err(MessageError::Invalid);
+            }
+            let verb = conf.message_map.get(verb).map_or(verb, |v| v.as_str());
+            if payload.is_empty() {
+                verb.to_string()
+            } else {
+                format!("{} {}", verb, payload)
+            }
+        }
+        None => {
+            let content = &splitted_msg.content;
+            let pattern_reply = if conf.message_map.contains_key(content) {
+                None
+            } else {
+                match_pattern(content, conf, &splitted_msg.room_group, client.id)
+            };
+
+            if let Some(reply) = pattern_reply {
+                reply
+            } else {
+                // trusted publishers can send messages outside of the authorized list
+                if client.trusted_publisher.is_none()
+                    && !is_authorized_message(content.clone(), conf)
+                {
+                    warn!(
+                        "Unauthorized messaage '{}', authorized {:?}",
+                        content, conf.authorized_messages
+                    );
+                    return Err(MessageError::Invalid);
+                }
+
+                conf.message_map.get(content).unwrap_or(content).clone()
+            }
+        }
+    };
 
-    Some(WebSocketAction {
-        send_message: msg_to_send,
+    Ok(WebSocketAction {
+        send_message: msg_to_send.into(),
         room_group: splitted_msg.room_group,
         room_config: conf.clone(),
     })
 }
+
+/// Binary frames start with the length of the room/group name (1 byte) followed by the name,
+/// a length of 0 uses the room group of the handshake path. The rest is the payload.
+pub fn handle_binary(
+    data: Bytes,
+    confs: &HashMap<String, RoomConfig>,
+    client: &ConnectionInfo,
+) -> Result<WebSocketAction, MessageError> {
+    let name_len = *data.first().ok_or(MessageError::Invalid)? as usize;
+    if data.len() < 1 + name_len {
+        warn!("Binary frame too short for its header");
+        return Err(MessageError::Invalid);
+    }
+
+    let room_group = if name_len == 0 {
+        client.handshake.room_group.clone()
+    } else {
+        std::str::from_utf8(&data[1..1 + name_len])
+            .ok()
+            .and_then(|name| str_to_roomgroup(confs, name))
+    }
+    .ok_or(MessageError::Invalid)?;
+    let conf = confs.get(&room_group.room).ok_or(MessageError::Invalid)?;
+
+    if client.trusted_publisher.is_none() && !room_allows(conf, &client.handshake) {
+        warn!(
+            "Connection {} not allowed in {} (origin {:?}, host {:?})",
+            client.id, conf.prefix, client.handshake.origin, client.handshake.host
+        );
+        return Err(MessageError::Invalid);
+    }
+
+    let payload = data.slice(1 + name_len..);
+    match conf.binary_max_size {
+        None => {
+            warn!("{} does not accept binary frames", conf.prefix);
+            return Err(MessageError::Invalid);
+        }
+        Some(max) if payload.len() > max => {
+            warn!(
+                "Binary frame of {} bytes refused in {} (max {})",
+                payload.len(),
+                conf.prefix,
+                max
+            );
+            return Err(MessageError::TooLarge);
+        }
+        Some(_) => {}
+    }
+
+    Ok(WebSocketAction {
+        send_message: Message::Binary(payload),
+        room_group,
+        room_config: conf.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backplane::LocalBackplane,
+        com::{ClientRoom, ServerRoom},
+        config_loader::RoomKind,
+    };
+    use regex::Regex;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn verb(max_length: Option<usize>, regex: Option<&str>, schema: Option<Value>) -> VerbConfig {
+        VerbConfig {
+            max_length,
+            regex: regex.map(|r| Regex::new(r).unwrap()),
+            schema: schema.map(|s| Arc::new(jsonschema::validator_for(&s).unwrap())),
+        }
+    }
+
+    #[test]
+    fn payloads_are_checked_against_the_schema() {
+        let conf = verb(
+            None,
+            None,
+            Some(json!({"type": "object", "required": ["player"]})),
+        );
+        assert!(is_valid_payload("turn", r#"{"player":3}"#, &conf));
+        assert!(!is_valid_payload("turn", r#"{"round":3}"#, &conf));
+        assert!(!is_valid_payload("turn", "not json", &conf));
+        // the schema requires a payload
+        assert!(!is_valid_payload("turn", "", &conf));
+    }
+
+    #[test]
+    fn payloads_are_checked_against_the_regex_and_length() {
+        let conf = verb(Some(8), Some("^[a-z]{1,16}$"), None);
+        assert!(is_valid_payload("rename", "alice", &conf));
+        assert!(!is_valid_payload("rename", "Alice!", &conf));
+        assert!(!is_valid_payload("rename", "alicealice", &conf));
+        assert!(!is_valid_payload("rename", "", &conf));
+    }
+
+    fn nokertu_42() -> RoomGroup {
+        RoomGroup {
+            full_roomgroup: "nokertu/42".to_string(),
+            room: "nokertu".to_string(),
+            group: Some("42".to_string()),
+            fetch_url: None,
+        }
+    }
+
+    #[test]
+    fn templates_use_captures_and_placeholders() {
+        let re = Regex::new("^(?<verb>[a-z]+) (.*)$").unwrap();
+        let caps = re.captures("typing alice").unwrap();
+        let reply = render_template(
+            "${verb}: $2 in ${room}/${group} by ${sender} ($$1) $3$",
+            &caps,
+            &nokertu_42(),
+            7,
+        );
+        assert_eq!(reply, "typing: alice in nokertu/42 by 7 ($1) $");
+    }
+
+    #[test]
+    fn captured_content_is_not_expanded() {
+        let re = Regex::new("^say (.*)$").unwrap();
+        let caps = re.captures("say ${room} $1").unwrap();
+        let reply = render_template("said $1", &caps, &nokertu_42(), 7);
+        assert_eq!(reply, "said ${room} $1");
+    }
+
+    /// `nokertu/42` member of `nokertu`, a room with a `destroySecret`
+    async fn destroy_with(token: &str) -> (bool, bool) {
+        let mut conf = RoomConfig::new("nokertu", RoomKind::Group("http://x/".to_string()));
+        conf.destroy_secret = Some("secret".to_string());
+        let confs = HashMap::from([("nokertu".to_string(), conf)]);
+        let smap = Arc::new(ServerMap::default());
+        let (c, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        smap.lock("nokertu/42").await.insert(
+            "nokertu/42".to_string(),
+            ServerRoom {
+                clients: Arc::new(vec![ClientRoom { c, global_id: 1 }]),
+            },
+        );
+        let backplane = LocalBackplane::new(Arc::clone(&smap));
+        let client = ConnectionInfo {
+            id: 2,
+            ..Default::default()
+        };
+        let destroyed = handle_group_destruction(
+            format!("nokertu/42 {}", token),
+            &confs,
+            &backplane,
+            &Webhooks::default(),
+            &client,
+        )
+        .await;
+        let disconnected = rx.try_recv() == Ok(Message::Close(None));
+        (destroyed, disconnected)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[tokio::test]
+    async fn groups_are_destroyed_with_a_valid_token() {
+        let expiry = now() + 60;
+        let signature = auth::sign("secret", format!("nokertu/42:{}", expiry).as_bytes());
+        let token = format!("{} {}", expiry, signature);
+        assert_eq!(destroy_with(&token).await, (true, true));
+    }
+
+    #[tokio::test]
+    async fn invalid_or_expired_tokens_are_refused() {
+        let expiry = now() + 60;
+        let other_group = auth::sign("secret", format!("nokertu/43:{}", expiry).as_bytes());
+        let other_secret = auth::sign("other", format!("nokertu/42:{}", expiry).as_bytes());
+        let without_expiry = auth::sign("secret", b"nokertu/42");
+        for token in [
+            format!("{} {}", expiry, other_group),
+            format!("{} {}", expiry, other_secret),
+            without_expiry,
+            format!("{} zz", expiry),
+        ] {
+            assert_eq!(destroy_with(&token).await, (false, false), "{}", token);
+        }
+
+        let expired = now() - 1;
+        let signature = auth::sign("secret", format!("nokertu/42:{}", expired).as_bytes());
+        let token = format!("{} {}", expired, signature);
+        assert_eq!(destroy_with(&token).await, (false, false));
+    }
+
+    #[test]
+    fn payloads_are_optional_without_rules() {
+        let conf = verb(Some(8), None, None);
+        assert!(is_valid_payload("leave", "", &conf));
+        assert!(is_valid_payload("leave", "now", &conf));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{ErrorResponse, Request},
+    http::{header, Response, StatusCode},
+};
+
+use tracing::warn;
+
+use crate::{
+    com::{str_to_roomgroup, RoomGroup},
+    config_loader::{GlobalConfig, RoomConfig},
+};
+
+/// Headers of the HTTP upgrade request kept for the room checks
+#[derive(Clone, Debug, Default)]
+pub struct HandshakeInfo {
+    pub origin: Option<String>,
+    pub host: Option<String>,
+    /// room group given by the request path (`/nokertu/42`), messages can leave out its prefix
+    pub room_group: Option<RoomGroup>,
+}
+
+/// longest upgrade request head read ahead of the handshake
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// Stream giving back the bytes read ahead, before reading the underlying stream
+pub struct Rewind<S> {
+    head: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.head.len() {
+            let n = buf.remaining().min(self.head.len() - self.pos);
+            buf.put_slice(&self.head[self.pos..self.pos + n]);
+            self.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Read the head of the upgrade request to know its path before the handshake,
+/// the handshake then reads it again from the returned stream
+pub async fn read_request_path<S: AsyncRead + Unpin>(
+    mut stream: S,
+) -> io::Result<(Option<String>, Rewind<S>)> {
+    let mut head = vec![];
+    let mut buf = [0; 2048];
+    while head.len() < MAX_REQUEST_HEAD {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        // only the new bytes are searched, with the end of the previous ones
+        let start = head.len().saturating_sub(3);
+        head.extend_from_slice(&buf[..n]);
+        if head[start..].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+    }
+
+    // GET /nokertu/42?x=y HTTP/1.1
+    let path = String::from_utf8_lossy(&head)
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .map(|target| target.split('?').next().unwrap_or_default().to_string());
+    Ok((
+        path,
+        Rewind {
+            head,
+            pos: 0,
+            inner: stream,
+        },
+    ))
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((h, port)) if port.chars().all(|c| c.is_ascii_digit()) => h,
+        _ => host,
+    }
+}
+
+/// `Origin` is only sent by browsers, other clients are not restricted by it
+pub fn origin_allowed(allowed: &[String], origin: Option<&str>) -> bool {
+    allowed.is_empty() || origin.is_none_or(|o| allowed.iter().any(|a| a == o))
+}
+
+/// `Host` may contain the port, entries without port match any port.
+/// Host names are case insensitive.
+pub fn host_allowed(allowed: &[String], host: Option<&str>) -> bool {
+    allowed.is_empty()
+        || host.is_some_and(|h| {
+            allowed
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(h) || a.eq_ignore_ascii_case(strip_port(h)))
+        })
+}
+
+pub fn room_allows(conf: &RoomConfig, handshake: &HandshakeInfo) -> bool {
+    origin_allowed(&conf.allowed_origins, handshake.origin.as_deref())
+        && host_allowed(&conf.allowed_hosts, handshake.host.as_deref())
+}
+
+pub type Rejection = (StatusCode, &'static str);
+
+pub fn rejection((status, reason): Rejection) -> ErrorResponse {
+    let mut resp = Response::new(Some(reason.to_string()));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Handshake callback checks, answered with the rejection status on error.
+/// Trusted publishers are not restricted by the room checks.
+pub fn check_request(
+    req: &Request,
+    conf: &GlobalConfig,
+    confs: &HashMap<String, RoomConfig>,
+    trusted: bool,
+) -> Result<HandshakeInfo, Rejection> {
+    let header_value = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let mut info = HandshakeInfo {
+        origin: header_value(header::ORIGIN),
+        host: header_value(header::HOST),
+        room_group: None,
+    };
+
+    if !origin_allowed(&conf.allowed_origins, info.origin.as_deref()) {
+        warn!("Handshake rejected: origin {:?} not allowed", info.origin);
+        return Err((StatusCode::FORBIDDEN, "origin not allowed"));
+    }
+    if !host_allowed(&conf.allowed_hosts, info.host.as_deref()) {
+        warn!("Handshake rejected: host {:?} not allowed", info.host);
+        return Err((StatusCode::FORBIDDEN, "host not allowed"));
+    }
+
+    let path = req.uri().path().trim_matches('/');
+    if !path.is_empty() {
+        let rg = match str_to_roomgroup(confs, path) {
+            Some(rg) => rg,
+            None => {
+                warn!("Handshake rejected: unknown room group {}", path);
+                return Err((StatusCode::NOT_FOUND, "unknown room group"));
+            }
+        };
+        let room_conf = confs
+            .get(&rg.room)
+            .ok_or((StatusCode::NOT_FOUND, "unknown room"))?;
+        if !trusted && !room_allows(room_conf, &info) {
+            warn!(
+                "Handshake rejected: {} not allowed (origin {:?}, host {:?})",
+                path, info.origin, info.host
+            );
+            return Err((StatusCode::FORBIDDEN, "room not allowed"));
+        }
+        info.room_group = Some(rg);
+    }
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn request_heads_are_read_again() {
+        let request = b"GET /nokertu/42?v=1 HTTP/1.1\r\nHost: x\r\n\r\nframe";
+        let (path, mut stream) = read_request_path(&request[..]).await.unwrap();
+        assert_eq!(path.as_deref(), Some("/nokertu/42"));
+        let mut replayed = vec![];
+        stream.read_to_end(&mut replayed).await.unwrap();
+        assert_eq!(replayed, request);
+    }
+
+    /// a client sending one byte at a time
+    struct ByteByByte(&'static [u8]);
+
+    impl AsyncRead for ByteByByte {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some((first, rest)) = self.0.split_first() {
+                buf.put_slice(&[*first]);
+                self.0 = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn heads_split_across_reads_end_at_the_blank_line() {
+        let head = b"GET /micasend HTTP/1.1\r\nHost: x\r\n\r\n";
+        let (path, stream) = read_request_path(ByteByByte(
+            b"GET /micasend HTTP/1.1\r\nHost: x\r\n\r\nframe",
+        ))
+        .await
+        .unwrap();
+        assert_eq!(path.as_deref(), Some("/micasend"));
+        assert_eq!(stream.head, head);
+        assert_eq!(stream.inner.0, b"frame");
+    }
+
+    #[test]
+    fn ports_are_stripped() {
+        assert_eq!(strip_port("example.com:8443"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn origins_are_checked_when_sent() {
+        let allowed = list(&["https://nokertu.magictintin.fr"]);
+        assert!(origin_allowed(
+            &allowed,
+            Some("https://nokertu.magictintin.fr")
+        ));
+        assert!(!origin_allowed(&allowed, Some("https://evil.example")));
+        assert!(origin_allowed(&allowed, None));
+        assert!(origin_allowed(&[], Some("https://evil.example")));
+    }
+
+    #[test]
+    fn hosts_match_any_port_and_case() {
+        let allowed = list(&["ws.magictintin.fr", "localhost:8080"]);
+        assert!(host_allowed(&allowed, Some("ws.magictintin.fr")));
+        assert!(host_allowed(&allowed, Some("ws.magictintin.fr:8443")));
+        assert!(host_allowed(&allowed, Some("WS.MagicTintin.fr")));
+        assert!(host_allowed(&allowed, Some("localhost:8080")));
+        assert!(!host_allowed(&allowed, Some("localhost:9000")));
+        assert!(!host_allowed(&allowed, Some("evil.example")));
+        assert!(!host_allowed(&allowed, None));
+        assert!(host_allowed(&[], None));
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+/// A connection joined a room group
+#[derive(Debug)]
+pub struct JoinEvent<'a> {
+    pub client_id: u64,
+    pub room_group: &'a str,
+}
+
+/// A message of a connection is broadcast to a room group
+#[derive(Debug)]
+pub struct BroadcastEvent<'a> {
+    pub client_id: u64,
+    pub room_group: &'a str,
+    pub message: &'a Message,
+}
+
+pub(crate) type JoinHook = Arc<dyn for<'a> Fn(&JoinEvent<'a>) + Send + Sync>;
+pub(crate) type BroadcastHook = Arc<dyn for<'a> Fn(&BroadcastEvent<'a>) + Send + Sync>;
+
+/// Callbacks of an embedding service, called from the connection tasks:
+/// they should return quickly
+#[derive(Clone, Default)]
+pub struct Hooks {
+    pub(crate) on_join: Vec<JoinHook>,
+    pub(crate) on_broadcast: Vec<BroadcastHook>,
+}
+
+impl Hooks {
+    pub(crate) fn joined(&self, event: JoinEvent) {
+        self.on_join.iter().for_each(|hook| hook(&event));
+    }
+
+    pub(crate) fn broadcast(&self, event: BroadcastEvent) {
+        self.on_broadcast.iter().for_each(|hook| hook(&event));
+    }
+}
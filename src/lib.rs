@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use backplane::TcpBackplane;
+use com::ServerState;
+use config_loader::{GlobalConfig, ListenerConfig, RoomConfig, RoomKind};
+use hooks::Hooks;
+use limits::ConnectionLimiter;
+use room_handler::{builtin_handler, RoomHandler};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{error, warn};
+use webhook::Webhooks;
+
+pub use com::RoomGroup;
+
+/// Internals measured by `benches/`, not a stable API
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::com::{send_to_local_group, ClientRoom, ServerMap, ServerRoom};
+}
+pub use hooks::{BroadcastEvent, JoinEvent};
+
+mod admin;
+mod auth;
+mod backplane;
+mod com;
+pub mod config_loader;
+mod handler;
+mod handshake;
+mod hooks;
+mod limits;
+mod offline;
+mod revalidate;
+pub mod room_handler;
+mod server;
+mod sharded;
+mod tls;
+mod webhook;
+
+static GLOBAL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn get_new_client_id() -> u64 {
+    // fetch_add provides atomic increment. No `unsafe` needed.
+    // Ordering specifies memory ordering constraints for concurrent access.
+    GLOBAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Embeddable server, configured in code or from `configs.json`:
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use chaline_websocket::{config_loader::*, ServerBuilder};
+///
+/// let server = ServerBuilder::new()
+///     .listener(ListenerConfig::plain("127.0.0.1:8080"))
+///     .room(RoomConfig::new("micasend", RoomKind::Broadcast))
+///     .on_join(|e| println!("{} joined {}", e.client_id, e.room_group))
+///     .start()
+///     .await?;
+/// server.shutdown().await;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: GlobalConfig,
+    room_configs: HashMap<String, RoomConfig>,
+    /// handlers of the custom room kinds, by type
+    room_kinds: HashMap<String, Arc<dyn RoomHandler>>,
+    hooks: Hooks,
+}
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Global configuration, the room files it lists are not loaded
+    pub fn config(mut self, config: GlobalConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn listener(mut self, listener: ListenerConfig) -> Self {
+        self.config.listeners.push(listener);
+        self
+    }
+
+    /// Add a room, replacing the room with the same prefix
+    pub fn room(mut self, conf: RoomConfig) -> Self {
+        self.room_configs.insert(conf.prefix.clone(), conf);
+        self
+    }
+
+    /// Behavior of the rooms of type `kind` (`RoomKind::Custom(kind)`)
+    pub fn room_kind(mut self, kind: &str, handler: impl RoomHandler + 'static) -> Self {
+        self.room_kinds.insert(kind.to_string(), Arc::new(handler));
+        self
+    }
+
+    /// Called when a connection joins a room group
+    pub fn on_join(mut self, hook: impl Fn(&JoinEvent) + Send + Sync + 'static) -> Self {
+        self.hooks.on_join.push(Arc::new(hook));
+        self
+    }
+
+    /// Called for each message broadcast to a room group by a connection
+    pub fn on_broadcast(mut self, hook: impl Fn(&BroadcastEvent) + Send + Sync + 'static) -> Self {
+        self.hooks.on_broadcast.push(Arc::new(hook));
+        self
+    }
+
+    /// Bind the listeners and serve them in background tasks
+    pub async fn start(self) -> anyhow::Result<ServerHandle> {
+        // shared list of clients and rooms, the same for every listener
+        let mut state = ServerState {
+            limiter: Arc::new(ConnectionLimiter::new(
+                self.config.max_connections,
+                self.config.max_connections_per_ip,
+            )),
+            config: Arc::new(self.config),
+            room_configs: Arc::new(self.room_configs),
+            hooks: Arc::new(self.hooks),
+            ..Default::default()
+        };
+
+        let mut room_handlers = HashMap::new();
+        for conf in state.room_configs.values() {
+            let handler = match &conf.kind {
+                RoomKind::Custom(kind) => self.room_kinds.get(kind).cloned(),
+                _ => builtin_handler(conf, &state.offline),
+            };
+            let Some(handler) = handler else {
+                anyhow::bail!("room {}: type '{}' unknown", conf.prefix, conf.kind);
+            };
+            room_handlers.insert(conf.prefix.clone(), handler);
+            if conf.anonymous_destroy && conf.destroy_secret.is_none() {
+                warn!(
+                    "room {}: any client can ask for the destruction of its groups (anonymousDestroy)",
+                    conf.prefix
+                );
+            }
+        }
+        state.room_handlers = Arc::new(room_handlers);
+
+        // bound before starting any task, a failed start leaves nothing running
+        let mut local_addrs = vec![];
+        let mut listeners = vec![];
+        for conf in state.config.listeners.iter() {
+            let listener = TcpListener::bind(&conf.address).await?;
+            local_addrs.push(listener.local_addr()?);
+            listeners.push((listener, conf.clone()));
+        }
+
+        // other nodes of the cluster receive the broadcasts and disconnections
+        if let Some(backplane_conf) = &state.config.backplane {
+            let started =
+                TcpBackplane::start(backplane_conf, state.rooms.clone(), state.shutdown.clone())
+                    .await;
+            state.backplane = match started {
+                Ok(backplane) => backplane,
+                Err(e) => {
+                    state.shutdown.cancel();
+                    return Err(e);
+                }
+            };
+        }
+        state.webhooks = Arc::new(Webhooks::start(&state.room_configs));
+        let mut tasks = listeners
+            .into_iter()
+            .map(|(listener, conf)| {
+                let state = state.clone();
+                state.tasks.clone().spawn(async move {
+                    let address = conf.address.clone();
+                    if let Err(e) = server::serve_listener(listener, conf, state).await {
+                        error!("Listener {} stopped: {:?}", address, e);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // live groups deleted by the backends are closed
+        for conf in state.room_configs.values() {
+            if let Some(every) = conf.revalidate_interval {
+                revalidate::spawn_revalidation(conf.clone(), state.clone(), every);
+            }
+        }
+
+        // webhooks of the backends
+        if let Some(admin_conf) = state.config.admin.clone() {
+            let state = state.clone();
+            tasks.push(state.tasks.clone().spawn(async move {
+                if let Err(e) = admin::serve_admin(admin_conf, state).await {
+                    error!("Admin API stopped: {:?}", e);
+                }
+            }));
+        }
+
+        Ok(ServerHandle {
+            local_addrs,
+            state,
+            listeners: tasks,
+        })
+    }
+}
+
+/// Running server
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    state: ServerState,
+    listeners: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Bound addresses of the listeners, in configuration order
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// TLS or WebSocket handshakes that failed or timed out (slow or broken clients)
+    pub fn aborted_handshakes(&self) -> u64 {
+        self.state.limiter.aborted_handshakes()
+    }
+
+    /// Wait until every listener stops
+    pub async fn wait(self) {
+        futures::future::join_all(self.listeners).await;
+    }
+
+    /// Stop the listeners, close the connections and wait for them
+    pub async fn shutdown(self) {
+        self.state.shutdown.cancel();
+        self.state.tasks.close();
+        self.state.tasks.wait().await;
+        // leave events of the closed connections included
+        self.state.webhooks.stop().await;
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tracing::warn;
+
+use crate::handshake::Rejection;
+
+/// Counts the open connections, globally and by remote IP, and the aborted handshakes
+#[derive(Default)]
+pub struct ConnectionLimiter {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    counts: Mutex<ConnectionCounts>,
+    aborted_handshakes: AtomicU64,
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Place taken by a connection, given back when dropped
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_total: Option<usize>, max_per_ip: Option<usize>) -> Self {
+        ConnectionLimiter {
+            max_total,
+            max_per_ip,
+            counts: Mutex::new(ConnectionCounts::default()),
+            aborted_handshakes: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a TLS or WebSocket handshake that failed or timed out, returns the total
+    pub fn count_aborted_handshake(&self) -> u64 {
+        self.aborted_handshakes.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn aborted_handshakes(&self) -> u64 {
+        self.aborted_handshakes.load(Ordering::Relaxed)
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionSlot, Rejection> {
+        // IPv4 clients of a [::] listener are seen as IPv4-mapped addresses
+        let ip = ip.to_canonical();
+        let mut counts = self.counts.lock().unwrap();
+
+        if self.max_total.is_some_and(|max| counts.total >= max) {
+            warn!("Connection from {} refused: too many connections", ip);
+            return Err((StatusCode::SERVICE_UNAVAILABLE, "too many connections"));
+        }
+        let ip_count = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if self.max_per_ip.is_some_and(|max| ip_count >= max) {
+            warn!(
+                "Connection from {} refused: too many connections from it",
+                ip
+            );
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many connections from this address",
+            ));
+        }
+
+        counts.total += 1;
+        counts.per_ip.insert(ip, ip_count + 1);
+        Ok(ConnectionSlot {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(count) = counts.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio_tungstenite::tungstenite::{protocol::Message, Bytes};
+use tracing::{error, info};
+
+use crate::{
+    com::RoomGroup,
+    config_loader::{OfflineConfig, RoomConfig},
+    sharded::ShardedMap,
+};
+
+/// Message kept for a group, ids only grow
+#[derive(Clone, Debug, PartialEq)]
+struct StoredMessage {
+    id: u64,
+    /// unix time in seconds
+    time: u64,
+    msg: Message,
+}
+
+/// Messages of the groups without their recipient, one json lines file per group:
+/// `<dir>/<room>/<hex encoded group>.jsonl`. They are kept until acknowledged.
+#[derive(Default)]
+pub struct OfflineStore {
+    /// operations on the file of a group are serialized
+    locks: ShardedMap<String, ()>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn group_file(conf: &OfflineConfig, rg: &RoomGroup) -> PathBuf {
+    let group = rg.group.as_deref().unwrap_or_default();
+    conf.dir
+        .join(&rg.room)
+        .join(format!("{}.jsonl", hex::encode(group)))
+}
+
+fn encode(stored: &StoredMessage) -> Option<String> {
+    let v = match &stored.msg {
+        Message::Text(txt) => json!({"id": stored.id, "time": stored.time, "text": txt.as_str()}),
+        Message::Binary(data) => {
+            json!({"id": stored.id, "time": stored.time, "binary": hex::encode(data)})
+        }
+        _ => return None,
+    };
+    Some(v.to_string())
+}
+
+fn decode(line: &str) -> Option<StoredMessage> {
+    let v: Value = serde_json::from_str(line).ok()?;
+    let msg = if let Some(txt) = v.get("text").and_then(|x| x.as_str()) {
+        Message::text(txt)
+    } else {
+        Message::Binary(Bytes::from(hex::decode(v.get("binary")?.as_str()?).ok()?))
+    };
+    Some(StoredMessage {
+        id: v.get("id")?.as_u64()?,
+        time: v.get("time")?.as_u64()?,
+        msg,
+    })
+}
+
+/// Messages of a group still in retention
+async fn read(conf: &OfflineConfig, rg: &RoomGroup) -> Vec<StoredMessage> {
+    let Ok(content) = tokio::fs::read_to_string(group_file(conf, rg)).await else {
+        return vec![];
+    };
+    let oldest = conf
+        .max_age
+        .map_or(0, |age| now().saturating_sub(age.as_secs()));
+    content
+        .lines()
+        .filter_map(decode)
+        .filter(|m| m.time >= oldest)
+        .collect()
+}
+
+/// Replace the file of a group, removed when there is nothing left
+async fn write(conf: &OfflineConfig, rg: &RoomGroup, messages: &[StoredMessage]) {
+    let path = group_file(conf, rg);
+    if messages.is_empty() {
+        let _ = tokio::fs::remove_file(&path).await;
+        return;
+    }
+
+    let mut content = String::new();
+    for line in messages.iter().filter_map(encode) {
+        content.push_str(&line);
+        content.push('\n');
+    }
+    // written aside then renamed, a crash never leaves a partial file
+    let tmp = path.with_extension("jsonl.tmp");
+    let result = async {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, &path).await
+    };
+    if let Err(e) = result.await {
+        error!("Offline messages of {} not saved: {}", rg.full_roomgroup, e);
+    }
+}
+
+impl OfflineStore {
+    /// Keep a message, the oldest ones are dropped over `maxMessages`
+    pub async fn push(&self, conf: &OfflineConfig, rg: &RoomGroup, msg: Message) {
+        let _guard = self.locks.lock(&rg.full_roomgroup).await;
+        let mut messages = read(conf, rg).await;
+
+        // unix time in ms, unless the clock went back
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let id = messages.last().map_or(millis, |m| millis.max(m.id + 1));
+        messages.push(StoredMessage {
+            id,
+            time: now(),
+            msg,
+        });
+        let excess = messages.len().saturating_sub(conf.max_messages);
+        messages.drain(..excess);
+
+        write(conf, rg, &messages).await;
+    }
+
+    /// Messages of the group not acknowledged yet, with their ids
+    pub async fn pending(&self, conf: &OfflineConfig, rg: &RoomGroup) -> Vec<(u64, Message)> {
+        let _guard = self.locks.lock(&rg.full_roomgroup).await;
+        read(conf, rg)
+            .await
+            .into_iter()
+            .map(|m| (m.id, m.msg))
+            .collect()
+    }
+
+    /// Delete the messages up to `id` (included)
+    pub async fn ack(&self, conf: &OfflineConfig, rg: &RoomGroup, id: u64) {
+        let _guard = self.locks.lock(&rg.full_roomgroup).await;
+        let mut messages = read(conf, rg).await;
+        messages.retain(|m| m.id > id);
+        write(conf, rg, &messages).await;
+    }
+}
+
+/// Connections subscribed to a group by their path (`/jirsend/bob`): its recipients
+pub type RecipientMap = ShardedMap<String, Vec<u64>>;
+
+/// Keep a message sent to a group of an individual room when its recipient is not connected
+pub async fn store_if_offline(
+    store: &OfflineStore,
+    recipients: &RecipientMap,
+    conf: &RoomConfig,
+    rg: &RoomGroup,
+    msg: &Message,
+) {
+    let Some(offline) = &conf.offline else {
+        return;
+    };
+    let has_recipient = recipients
+        .lock(&rg.full_roomgroup)
+        .await
+        .contains_key(&rg.full_roomgroup);
+    if !has_recipient {
+        info!("Recipient of {} offline, message kept", rg.full_roomgroup);
+        store.push(offline, rg, msg.clone()).await;
+    }
+}
+
+/// Kept messages of a group for a new member, followed by
+/// `+room/group <id>`: the id to acknowledge them with
+pub async fn offline_messages(
+    store: &OfflineStore,
+    conf: &RoomConfig,
+    rg: &RoomGroup,
+) -> Vec<Message> {
+    let Some(offline) = &conf.offline else {
+        return vec![];
+    };
+    let messages = store.pending(offline, rg).await;
+    let Some((last_id, _)) = messages.last() else {
+        return vec![];
+    };
+    let marker = Message::text(format!("+{} {}", rg.full_roomgroup, last_id));
+    info!(
+        "{} offline messages of {} sent",
+        messages.len(),
+        rg.full_roomgroup
+    );
+    messages
+        .into_iter()
+        .map(|(_, msg)| msg)
+        .chain([marker])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_loader::RoomKind;
+    use std::{collections::HashMap, time::Duration};
+
+    fn offline_room(name: &str, max_messages: usize) -> (RoomConfig, RoomGroup) {
+        let dir = std::env::temp_dir().join(format!("chaline-offline-{}-{}", name, now()));
+        let mut conf = RoomConfig::new("jirsend", RoomKind::Individual("http://x/".to_string()));
+        conf.offline = Some(OfflineConfig {
+            dir,
+            max_messages,
+            max_age: None,
+        });
+        let mut confs = HashMap::new();
+        confs.insert("jirsend".to_string(), conf.clone());
+        let rg = crate::com::str_to_roomgroup(&confs, "jirsend/bob").unwrap();
+        (conf, rg)
+    }
+
+    #[tokio::test]
+    async fn messages_are_kept_until_acknowledged() {
+        let (conf, rg) = offline_room("ack", 10);
+        let offline = conf.offline.as_ref().unwrap();
+        let store = OfflineStore::default();
+
+        store.push(offline, &rg, Message::text("one")).await;
+        store
+            .push(offline, &rg, Message::Binary(Bytes::from_static(&[1, 2])))
+            .await;
+        store.push(offline, &rg, Message::text("three")).await;
+
+        let pending = store.pending(offline, &rg).await;
+        assert_eq!(pending.len(), 3);
+        assert!(pending.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(pending[1].1, Message::Binary(Bytes::from_static(&[1, 2])));
+
+        store.ack(offline, &rg, pending[1].0).await;
+        let left = store.pending(offline, &rg).await;
+        assert_eq!(left, vec![pending[2].clone()]);
+
+        store.ack(offline, &rg, pending[2].0).await;
+        assert!(store.pending(offline, &rg).await.is_empty());
+        assert!(!group_file(offline, &rg).exists());
+        let _ = std::fs::remove_dir_all(&offline.dir);
+    }
+
+    #[tokio::test]
+    async fn oldest_messages_are_dropped_over_the_limit() {
+        let (conf, rg) = offline_room("limit", 2);
+        let offline = conf.offline.as_ref().unwrap();
+        let store = OfflineStore::default();
+
+        for txt in ["one", "two", "three"] {
+            store.push(offline, &rg, Message::text(txt)).await;
+        }
+        let pending = store.pending(offline, &rg).await;
+        let texts = pending.into_iter().map(|(_, m)| m).collect::<Vec<_>>();
+        assert_eq!(texts, vec![Message::text("two"), Message::text("three")]);
+
+        // expired messages are not delivered
+        let old = StoredMessage {
+            id: 1,
+            time: now() - 3600,
+            msg: Message::text("old"),
+        };
+        let recent = StoredMessage {
+            id: 2,
+            time: now(),
+            msg: Message::text("recent"),
+        };
+        write(offline, &rg, &[old, recent]).await;
+        let mut expiring = offline.clone();
+        expiring.max_age = Some(Duration::from_secs(60));
+        let pending = store.pending(&expiring, &rg).await;
+        assert_eq!(pending, vec![(2, Message::text("recent"))]);
+        let _ = std::fs::remove_dir_all(&offline.dir);
+    }
+
+    #[tokio::test]
+    async fn messages_are_kept_while_the_recipient_is_offline() {
+        let (conf, rg) = offline_room("recipient", 10);
+        let offline = conf.offline.as_ref().unwrap();
+        let store = OfflineStore::default();
+        let recipients = RecipientMap::default();
+
+        store_if_offline(&store, &recipients, &conf, &rg, &Message::text("hi")).await;
+        assert_eq!(store.pending(offline, &rg).await.len(), 1);
+        assert!(group_file(offline, &rg).starts_with(offline.dir.join("jirsend")));
+        // the path subscription of the recipient receives it
+        recipients
+            .lock(&rg.full_roomgroup)
+            .await
+            .insert(rg.full_roomgroup.clone(), vec![2]);
+        store_if_offline(&store, &recipients, &conf, &rg, &Message::text("hi")).await;
+        assert_eq!(store.pending(offline, &rg).await.len(), 1);
+        let _ = std::fs::remove_dir_all(&offline.dir);
+    }
+}
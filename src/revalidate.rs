@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::{
+    com::{close_group, does_room_group_exists_retry, ServerState, GROUP_CLOSED_MESSAGE},
+    config_loader::{RoomConfig, RoomKind, WebhookEvent},
+};
+
+/// Periodically check the live groups of a room against its `fetchURL`,
+/// groups deleted by the backend are closed.
+pub fn spawn_revalidation(conf: RoomConfig, state: ServerState, every: Duration) {
+    let url = match &conf.kind {
+        RoomKind::Group(url) | RoomKind::Individual(url) => url.clone(),
+        RoomKind::Broadcast | RoomKind::Custom(_) => {
+            warn!("{}: room without fetchURL to revalidate", conf.prefix);
+            return;
+        }
+    };
+    let group_prefix = format!("{}/", conf.prefix);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(every);
+        // the first tick is immediate, groups were just validated
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => return,
+            }
+
+            let groups = state
+                .rooms
+                .keys()
+                .await
+                .into_iter()
+                .filter_map(|rg| rg.strip_prefix(&group_prefix).map(|g| g.to_string()))
+                .collect::<Vec<_>>();
+
+            for group in groups {
+                match does_room_group_exists_retry(&url, &group).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        let full_roomgroup = format!("{}{}", group_prefix, group);
+                        info!("{} does not exist anymore, closing it", full_roomgroup);
+                        state
+                            .webhooks
+                            .notify(WebhookEvent::Destroy, &full_roomgroup, None, None);
+                        close_group(
+                            state.backplane.as_ref(),
+                            &full_roomgroup,
+                            GROUP_CLOSED_MESSAGE.into(),
+                        )
+                        .await;
+                    }
+                    // backend unreachable or failing: keep the group, it will be checked again
+                    Err(e) => warn!("Revalidation of {}{} failed: {:?}", url, group, e),
+                }
+            }
+        }
+    });
+}
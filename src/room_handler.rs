@@ -0,0 +1,172 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tracing::warn;
+
+use crate::{
+    com::{does_room_group_exists, RoomGroup},
+    config_loader::{RoomConfig, RoomKind},
+    offline::{offline_messages, store_if_offline, OfflineStore, RecipientMap},
+};
+
+/// A connection joins, sends to or leaves a room group
+pub struct RoomEvent<'a> {
+    pub client_id: u64,
+    pub room_group: &'a RoomGroup,
+    pub config: &'a RoomConfig,
+    /// joining the room group of the request path
+    pub path_subscription: bool,
+}
+
+/// Message sent because of an event
+#[derive(Clone, Debug, PartialEq)]
+pub enum Delivery {
+    /// to every member of a room group, on every node
+    Group(String, Message),
+    /// only to the connection of the event
+    Client(Message),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// the connection is closed
+    Reject,
+    Deliver(Vec<Delivery>),
+}
+
+/// Behavior of a room kind. Messages reaching `on_message` already passed the rules
+/// of the room configuration (authorized messages, map, verbs...).
+pub trait RoomHandler: Send + Sync {
+    /// A client joins a group without any member on this server, is it valid?
+    fn on_group_create<'a>(&'a self, _rg: &'a RoomGroup) -> BoxFuture<'a, bool> {
+        Box::pin(async { true })
+    }
+
+    /// Called once the group is validated and the connection added, `Reject` removes it
+    fn on_join<'a>(&'a self, _event: &'a RoomEvent<'a>) -> BoxFuture<'a, Verdict> {
+        Box::pin(async { Verdict::Deliver(vec![]) })
+    }
+
+    /// Sent to the room group of the event by default
+    fn on_message<'a>(&'a self, event: &'a RoomEvent<'a>, msg: Message) -> BoxFuture<'a, Verdict> {
+        let rg = event.room_group.full_roomgroup.clone();
+        Box::pin(async move { Verdict::Deliver(vec![Delivery::Group(rg, msg)]) })
+    }
+
+    /// Left explicitly or disconnected, `Client` deliveries are dropped on disconnection
+    fn on_leave<'a>(&'a self, _event: &'a RoomEvent<'a>) -> BoxFuture<'a, Vec<Delivery>> {
+        Box::pin(async { vec![] })
+    }
+}
+
+/// Every group exists
+pub struct BroadcastRoom;
+
+impl RoomHandler for BroadcastRoom {}
+
+/// Groups are checked against the `fetchURL` of the room
+pub struct GroupRoom {
+    pub fetch_url: String,
+}
+
+async fn group_exists(url: &str, rg: &RoomGroup) -> bool {
+    let Some(group) = &rg.group else {
+        return false; // this case shouldn't appear
+    };
+    match does_room_group_exists(url, group).await {
+        Ok(v) => v,
+        Err(e) => {
+            warn!(
+                "Error checking remote group existence for {} ({}): {:?}",
+                url, group, e
+            );
+            false
+        }
+    }
+}
+
+impl RoomHandler for GroupRoom {
+    fn on_group_create<'a>(&'a self, rg: &'a RoomGroup) -> BoxFuture<'a, bool> {
+        Box::pin(group_exists(&self.fetch_url, rg))
+    }
+}
+
+/// Group room where messages are kept for offline recipients (with `offline` configured)
+pub struct IndividualRoom {
+    pub fetch_url: String,
+    recipients: RecipientMap,
+    store: Arc<OfflineStore>,
+}
+
+impl RoomHandler for IndividualRoom {
+    fn on_group_create<'a>(&'a self, rg: &'a RoomGroup) -> BoxFuture<'a, bool> {
+        Box::pin(group_exists(&self.fetch_url, rg))
+    }
+
+    /// the connection of the recipient gets its kept messages
+    fn on_join<'a>(&'a self, event: &'a RoomEvent<'a>) -> BoxFuture<'a, Verdict> {
+        Box::pin(async move {
+            if !event.path_subscription {
+                return Verdict::Deliver(vec![]);
+            }
+            let rg = &event.room_group.full_roomgroup;
+            self.recipients
+                .lock(rg)
+                .await
+                .entry(rg.clone())
+                .or_default()
+                .push(event.client_id);
+            let messages = offline_messages(&self.store, event.config, event.room_group).await;
+            Verdict::Deliver(messages.into_iter().map(Delivery::Client).collect())
+        })
+    }
+
+    fn on_message<'a>(&'a self, event: &'a RoomEvent<'a>, msg: Message) -> BoxFuture<'a, Verdict> {
+        Box::pin(async move {
+            store_if_offline(
+                &self.store,
+                &self.recipients,
+                event.config,
+                event.room_group,
+                &msg,
+            )
+            .await;
+            let rg = event.room_group.full_roomgroup.clone();
+            Verdict::Deliver(vec![Delivery::Group(rg, msg)])
+        })
+    }
+
+    fn on_leave<'a>(&'a self, event: &'a RoomEvent<'a>) -> BoxFuture<'a, Vec<Delivery>> {
+        Box::pin(async move {
+            let rg = &event.room_group.full_roomgroup;
+            let mut guard = self.recipients.lock(rg).await;
+            if let Some(ids) = guard.get_mut(rg) {
+                ids.retain(|id| *id != event.client_id);
+                if ids.is_empty() {
+                    guard.remove(rg);
+                }
+            }
+            vec![]
+        })
+    }
+}
+
+/// Handler of the built-in kinds, None for custom kinds
+pub fn builtin_handler(
+    conf: &RoomConfig,
+    store: &Arc<OfflineStore>,
+) -> Option<Arc<dyn RoomHandler>> {
+    match &conf.kind {
+        RoomKind::Broadcast => Some(Arc::new(BroadcastRoom)),
+        RoomKind::Group(url) => Some(Arc::new(GroupRoom {
+            fetch_url: url.clone(),
+        })),
+        RoomKind::Individual(url) => Some(Arc::new(IndividualRoom {
+            fetch_url: url.clone(),
+            recipients: RecipientMap::default(),
+            store: Arc::clone(store),
+        })),
+        RoomKind::Custom(_) => None,
+    }
+}
@@ -0,0 +1,655 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::accept_hdr_async_with_config;
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::protocol::{
+    frame::coding::CloseCode, CloseFrame, Message, WebSocketConfig,
+};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace, warn};
+
+use crate::{
+    com::{
+        add_client_to_rg, broadcast_to_group, can_join, is_member, rm_client, rm_client_from_rg,
+        str_to_roomgroup, ClientRoom, ConnectionInfo, RoomGroup, ServerState,
+    },
+    config_loader::{ListenerConfig, RoomConfig, WebhookEvent},
+    get_new_client_id,
+    handler::{
+        handle_binary, handle_group_destruction, handle_group_leave, handle_message,
+        handle_offline_ack, MessageError,
+    },
+    handshake::{check_request, read_request_path, rejection, HandshakeInfo, Rejection},
+    hooks::{BroadcastEvent, JoinEvent},
+    limits::ConnectionSlot,
+    room_handler::{Delivery, RoomEvent, Verdict},
+    tls::{client_verifier, spawn_cert_reloader, trusted_publisher, ReloadingCertResolver},
+};
+
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// time left to the writer of a connection once the server shuts down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+/// TLS refusals of a listener in progress at most, further sockets over the limits are dropped
+const MAX_TLS_REFUSALS: usize = 64;
+
+/// Accept connections on one listener until it fails or the server shuts down.
+/// Every listener shares the same `ServerState`.
+pub async fn serve_listener(
+    listener: TcpListener,
+    conf: ListenerConfig,
+    state: ServerState,
+) -> anyhow::Result<()> {
+    let acceptor = match &conf.tls {
+        Some(tls) => {
+            // certificate is reloaded on renewal (SIGHUP or file change)
+            let resolver = Arc::new(ReloadingCertResolver::new(&tls.cert, &tls.key)?);
+            spawn_cert_reloader(Arc::clone(&resolver), state.shutdown.clone(), &state.tasks)?;
+
+            // TLS server, with optional client certificates for trusted publishers
+            let builder = ServerConfig::builder();
+            let config = match &tls.client_ca {
+                Some(ca) => builder
+                    .with_client_cert_verifier(client_verifier(ca)?)
+                    .with_cert_resolver(resolver),
+                None => builder.with_no_client_auth().with_cert_resolver(resolver),
+            };
+            Some(TlsAcceptor::from(Arc::new(config)))
+        }
+        None => None,
+    };
+    let trusted_publishers = Arc::new(
+        conf.tls
+            .as_ref()
+            .map(|tls| tls.trusted_publishers.clone())
+            .unwrap_or_default(),
+    );
+
+    let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+    println!("Listening on {}://{}", scheme, listener.local_addr()?);
+
+    let tls_timeout = state
+        .config
+        .tls_handshake_timeout
+        .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+    let tls_refusals = Arc::new(Semaphore::new(MAX_TLS_REFUSALS));
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(_) => break,
+            },
+            _ = state.shutdown.cancelled() => break,
+        };
+        // over the limits, sockets are answered before any WebSocket handshake
+        let slot = match state.limiter.try_acquire(addr.ip()) {
+            Ok(slot) => slot,
+            Err(rejection) => {
+                match &acceptor {
+                    None => refuse_plain(stream, rejection),
+                    // a TLS client can only read the answer after a TLS handshake
+                    Some(acceptor) => match Arc::clone(&tls_refusals).try_acquire_owned() {
+                        Ok(permit) => {
+                            let acceptor = acceptor.clone();
+                            state.tasks.spawn(async move {
+                                let refusal = refuse_tls(acceptor, stream, rejection);
+                                let _ = timeout(tls_timeout, refusal).await;
+                                drop(permit);
+                            });
+                        }
+                        Err(_) => warn!("Connection from {} dropped: too many refusals", addr),
+                    },
+                }
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        let trusted_publishers = Arc::clone(&trusted_publishers);
+
+        state.tasks.clone().spawn(async move {
+            match acceptor {
+                Some(acceptor) => {
+                    // accept TLS connection
+                    let tls_stream = match timeout(tls_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(tls_stream)) => tls_stream,
+                        Ok(Err(err)) => {
+                            let aborted = state.limiter.count_aborted_handshake();
+                            error!("TLS handshake failed ({} aborted): {}", aborted, err);
+                            return;
+                        }
+                        Err(_) => {
+                            let aborted = state.limiter.count_aborted_handshake();
+                            warn!(
+                                "TLS handshake from {} timed out ({} aborted)",
+                                addr, aborted
+                            );
+                            return;
+                        }
+                    };
+                    // certificates are only there if verified against the client CA
+                    let publisher = tls_stream
+                        .get_ref()
+                        .1
+                        .peer_certificates()
+                        .and_then(|certs| trusted_publisher(certs, &trusted_publishers));
+                    handle_connection(tls_stream, state, publisher, slot).await;
+                }
+                None => handle_connection(stream, state, None, slot).await,
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Best effort HTTP answer to a refused plain connection, without waiting for its request
+fn refusal_response((status, reason): Rejection) -> String {
+    format!(
+        "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        reason.len(),
+        reason
+    )
+}
+
+fn refuse_plain(stream: TcpStream, rejection: Rejection) {
+    // tokio only writes once the socket was polled, the std socket is written right away
+    if let Ok(mut stream) = stream.into_std() {
+        let _ = stream.write(refusal_response(rejection).as_bytes());
+    }
+}
+
+/// Answer over TLS, the caller bounds it with the TLS handshake timeout
+async fn refuse_tls(acceptor: TlsAcceptor, stream: TcpStream, rejection: Rejection) {
+    let answer = async {
+        let mut tls_stream = acceptor.accept(stream).await?;
+        tls_stream
+            .write_all(refusal_response(rejection).as_bytes())
+            .await?;
+        tls_stream.shutdown().await
+    };
+    if let Err(e) = answer.await {
+        trace!("TLS refusal not delivered: {}", e);
+    }
+}
+
+/// Never resolves without a deadline
+async fn deadline(at: Option<Instant>) {
+    match at {
+        Some(at) => sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Join a room group, its room handler can refuse it or send messages once joined
+async fn join_room_group(state: &ServerState, event: &RoomEvent<'_>, client: &ClientRoom) -> bool {
+    let rg = event.room_group;
+    let Some(handler) = state.room_handlers.get(&rg.room) else {
+        return false;
+    };
+    // the group is validated (fetchURL) and the member added before its room sees the join
+    let joined = add_client_to_rg(
+        &state.rooms,
+        &state.clients,
+        &state.pending,
+        handler.as_ref(),
+        rg.clone(),
+        client.clone(),
+    )
+    .await;
+    if !joined {
+        return false;
+    }
+    let deliveries = match handler.on_join(event).await {
+        Verdict::Deliver(deliveries) => deliveries,
+        Verdict::Reject => {
+            warn!(
+                "Client ({}) refused in {} by its room",
+                event.client_id, rg.full_roomgroup
+            );
+            rm_client_from_rg(
+                &state.rooms,
+                &state.clients,
+                event.client_id,
+                &rg.full_roomgroup,
+            )
+            .await;
+            return false;
+        }
+    };
+    state.hooks.joined(JoinEvent {
+        client_id: event.client_id,
+        room_group: &rg.full_roomgroup,
+    });
+    state.webhooks.notify(
+        WebhookEvent::Join,
+        &rg.full_roomgroup,
+        Some(event.client_id),
+        None,
+    );
+    deliver(state, event.client_id, deliveries, Some(client)).await;
+    true
+}
+
+/// The connection left a room group, explicitly (with its `client`) or by disconnecting
+async fn leave_room_group(
+    state: &ServerState,
+    client_id: u64,
+    rg: &RoomGroup,
+    client: Option<&ClientRoom>,
+) {
+    state.webhooks.notify(
+        WebhookEvent::Leave,
+        &rg.full_roomgroup,
+        Some(client_id),
+        None,
+    );
+    let (Some(handler), Some(config)) = (
+        state.room_handlers.get(&rg.room),
+        state.room_configs.get(&rg.room),
+    ) else {
+        return;
+    };
+    let event = RoomEvent {
+        client_id,
+        room_group: rg,
+        config,
+        path_subscription: false,
+    };
+    let deliveries = handler.on_leave(&event).await;
+    deliver(state, client_id, deliveries, client).await;
+}
+
+/// Send the messages of a room handler, `Client` ones need a connection
+async fn deliver(
+    state: &ServerState,
+    client_id: u64,
+    deliveries: Vec<Delivery>,
+    client: Option<&ClientRoom>,
+) {
+    for delivery in deliveries {
+        match delivery {
+            Delivery::Group(rg, msg) => {
+                state.hooks.broadcast(BroadcastEvent {
+                    client_id,
+                    room_group: &rg,
+                    message: &msg,
+                });
+                state
+                    .webhooks
+                    .notify(WebhookEvent::Message, &rg, Some(client_id), Some(&msg));
+                broadcast_to_group(state.backplane.as_ref(), &rg, msg).await;
+            }
+            Delivery::Client(msg) => {
+                if let Some(client) = client {
+                    let _ = client.c.send(msg);
+                }
+            }
+        }
+    }
+}
+
+/// `+room/group <id>` and `~room/group` name a room group, other messages starting with
+/// `+` or `~` are contents (for the room group of the path)
+fn command<'a>(
+    configs: &HashMap<String, RoomConfig>,
+    txt: &'a str,
+    prefix: char,
+) -> Option<&'a str> {
+    let args = txt.strip_prefix(prefix)?;
+    let name = args.split_whitespace().next()?;
+    let room = name.split('/').next().unwrap_or_default();
+    // checked first, unknown names are not logged as invalid room groups
+    (configs.contains_key(room) && str_to_roomgroup(configs, name).is_some()).then_some(args)
+}
+
+/// Size limit of the messages of a room, binary frames included
+fn room_message_limit(conf: &RoomConfig) -> Option<usize> {
+    let text = conf.max_message_size?;
+    // binary frames carry the room group name (up to 256 bytes) before their payload
+    Some(conf.binary_max_size.map_or(text, |max| text.max(max + 256)))
+}
+
+/// Lowest of two optional limits, None being no limit
+fn lowest(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+async fn handle_connection<S>(
+    stream: S,
+    state: ServerState,
+    trusted_publisher: Option<String>,
+    // given back when the connection ends
+    _slot: ConnectionSlot,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let configs = &*state.room_configs;
+    let global_config = &*state.config;
+
+    // upgrade to WebSocket, checking the request headers and path
+    let mut handshake = HandshakeInfo::default();
+    let trusted = trusted_publisher.is_some();
+    // tungstenite imposes its (large) error response type
+    #[allow(clippy::result_large_err)]
+    let check = |req: &Request, resp: Response| {
+        handshake = check_request(req, global_config, configs, trusted).map_err(rejection)?;
+        Ok(resp)
+    };
+    let upgrade_timeout = global_config
+        .upgrade_timeout
+        .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+    let upgrade = async {
+        // the room of the request path limits the size of every message
+        let (path, stream) = read_request_path(stream).await?;
+        let room_limit = path
+            .and_then(|p| str_to_roomgroup(configs, p.trim_matches('/')))
+            .and_then(|rg| configs.get(&rg.room))
+            .and_then(room_message_limit);
+        let ws_config = WebSocketConfig::default()
+            .max_message_size(lowest(global_config.max_message_size, room_limit))
+            .max_frame_size(lowest(global_config.max_frame_size, room_limit));
+        accept_hdr_async_with_config(stream, check, Some(ws_config)).await
+    };
+    let ws_stream = match timeout(upgrade_timeout, upgrade).await {
+        Ok(Ok(ws)) => ws,
+        // refused by the checks, answered with an HTTP error
+        Ok(Err(err @ WsError::Http(_))) => {
+            error!("WebSocket handshake failed: {}", err);
+            return;
+        }
+        Ok(Err(err)) => {
+            let aborted = state.limiter.count_aborted_handshake();
+            error!("WebSocket handshake failed ({} aborted): {}", aborted, err);
+            return;
+        }
+        Err(_) => {
+            let aborted = state.limiter.count_aborted_handshake();
+            warn!("WebSocket handshake timed out ({} aborted)", aborted);
+            return;
+        }
+    };
+    let client_id = get_new_client_id();
+    println!("New WebSocket connection ({}) established", client_id);
+    if let Some(subject) = &trusted_publisher {
+        info!(
+            "Connection {} is the trusted publisher '{}'",
+            client_id, subject
+        );
+    }
+    let client_info = ConnectionInfo {
+        id: client_id,
+        trusted_publisher,
+        handshake,
+    };
+
+    let ServerState {
+        clients,
+        rooms,
+        offline,
+        backplane,
+        shutdown,
+        ..
+    } = state.clone();
+
+    // Split the WebSocket stream into read and write halves
+    let (mut write, mut read) = ws_stream.split();
+
+    // messages to this client, from any task
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let client_r = ClientRoom {
+        c: tx,
+        global_id: client_id,
+    };
+
+    // sending messages to the client
+    let send_task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if write.send(msg).await.is_err() {
+                break; // Client disconnected
+            }
+        }
+    });
+
+    // pre-subscribe to the room group of the request path
+    if let Some(rg) = &client_info.handshake.room_group {
+        let subscribed = match configs.get(&rg.room) {
+            Some(config) => {
+                let event = RoomEvent {
+                    client_id,
+                    room_group: rg,
+                    config,
+                    path_subscription: true,
+                };
+                join_room_group(&state, &event, &client_r).await
+            }
+            None => false,
+        };
+        if !subscribed {
+            let _ = client_r.c.send(Message::Close(None));
+            warn!(
+                "Closing connection {}: invalid group {} in path",
+                client_id, rg.full_roomgroup
+            );
+            rm_client(&rooms, &clients, client_id).await;
+            drop(client_r);
+            finish_writer(send_task, &shutdown, client_id).await;
+            return;
+        }
+    }
+
+    // receiving messages from the client
+    // connections not subscribed by their path must send a first message in time
+    let mut first_deadline = match (
+        &client_info.handshake.room_group,
+        global_config.first_message_timeout,
+    ) {
+        (None, Some(t)) => Some(Instant::now() + t),
+        _ => None,
+    };
+
+    loop {
+        let next = tokio::select! {
+            next = read.next() => next,
+            _ = deadline(first_deadline) => {
+                warn!("Closing connection {}: no message in time", client_id);
+                let _ = client_r.c.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "no message in time".into(),
+                })));
+                break;
+            }
+            _ = shutdown.cancelled() => {
+                let _ = client_r.c.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "server shutting down".into(),
+                })));
+                break;
+            }
+        };
+        let Some(read_result) = next else {
+            break;
+        };
+        let msg = match read_result {
+            Ok(msg) => msg,
+            // over the global message or frame size limits
+            Err(WsError::Capacity(e)) => {
+                warn!("Closing connection {}: {}", client_id, e);
+                let _ = client_r.c.send(MessageError::TooLarge.close_message());
+                break;
+            }
+            Err(_) => break,
+        };
+
+        if msg.is_text() || msg.is_binary() {
+            first_deadline = None;
+        }
+
+        let action = match msg {
+            Message::Text(txt) => {
+                trace!("Received: {}", txt);
+
+                if let Some(room_group_name) = txt.strip_prefix("-") {
+                    if handle_group_destruction(
+                        room_group_name.to_string(),
+                        configs,
+                        backplane.as_ref(),
+                        &state.webhooks,
+                        &client_info,
+                    )
+                    .await
+                    {
+                        // a trusted publisher keeps its connection to manage other groups
+                        if client_info.trusted_publisher.is_some() {
+                            continue;
+                        }
+                        warn!("Closing connection {}: group is closing...", client_id);
+                    } else {
+                        let _ = client_r.c.send(Message::Close(None));
+                        warn!(
+                            "Closing connection {}: client was trying to close wrong group...",
+                            client_id
+                        );
+                    };
+
+                    break;
+                } else if let Some(ack) = command(configs, &txt, '+') {
+                    if handle_offline_ack(ack, configs, &offline, &client_info).await {
+                        continue;
+                    }
+                    Err(MessageError::Invalid)
+                } else if let Some(room_group_name) = command(configs, &txt, '~') {
+                    match handle_group_leave(
+                        room_group_name,
+                        configs,
+                        &rooms,
+                        &clients,
+                        &client_info,
+                    )
+                    .await
+                    {
+                        Ok(Some(rg)) => {
+                            leave_room_group(&state, client_id, &rg, Some(&client_r)).await;
+                            continue;
+                        }
+                        Ok(None) => continue,
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    handle_message(txt.to_string(), configs, &client_info)
+                }
+            }
+            Message::Binary(data) => {
+                trace!("Received: {} bytes", data.len());
+                handle_binary(data, configs, &client_info)
+            }
+            _ => continue,
+        };
+
+        match action {
+            Ok(res) => {
+                let max_groups = global_config.max_groups_per_client;
+                if !can_join(
+                    &clients,
+                    client_id,
+                    &res.room_group.full_roomgroup,
+                    max_groups,
+                )
+                .await
+                {
+                    warn!(
+                        "Closing connection {}: too many groups to join {}",
+                        client_id, res.room_group.full_roomgroup
+                    );
+                    let _ = client_r.c.send(MessageError::TooManyGroups.close_message());
+                    break;
+                }
+                let event = RoomEvent {
+                    client_id,
+                    room_group: &res.room_group,
+                    config: &res.room_config,
+                    path_subscription: false,
+                };
+                if !is_member(&clients, client_id, &res.room_group.full_roomgroup).await
+                    && !join_room_group(&state, &event, &client_r).await
+                {
+                    let _ = client_r.c.send(Message::Close(None));
+                    warn!(
+                        "Closing connection {}: error while connecting to invalid group...",
+                        client_id
+                    );
+                    break;
+                }
+                let verdict = match state.room_handlers.get(&res.room_group.room) {
+                    Some(handler) => handler.on_message(&event, res.send_message).await,
+                    None => Verdict::Reject,
+                };
+                match verdict {
+                    Verdict::Deliver(deliveries) => {
+                        deliver(&state, client_id, deliveries, Some(&client_r)).await
+                    }
+                    Verdict::Reject => {
+                        warn!(
+                            "Closing connection {}: message refused by {}",
+                            client_id, res.room_group.room
+                        );
+                        let _ = client_r.c.send(MessageError::Invalid.close_message());
+                        break;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Closing connection {}: unknown/invalid message ({:?})",
+                    client_id, e
+                );
+                let _ = client_r.c.send(e.close_message()); //tx.
+                break;
+            }
+        }
+    }
+
+    info!("Socket connection ended");
+
+    // remove the client from the shared list
+    for name in rm_client(&rooms, &clients, client_id).await {
+        if let Some(rg) = str_to_roomgroup(configs, &name) {
+            leave_room_group(&state, client_id, &rg, None).await;
+        }
+    }
+
+    // wait for the send task to finish, it ends once every sender is dropped
+    drop(client_r);
+    finish_writer(send_task, &shutdown, client_id).await;
+}
+
+/// Wait for the writer of a connection, a client not reading can't hold the shutdown
+async fn finish_writer(mut send_task: JoinHandle<()>, shutdown: &CancellationToken, id: u64) {
+    tokio::select! {
+        _ = &mut send_task => return,
+        _ = shutdown.cancelled() => {}
+    }
+    if timeout(SHUTDOWN_GRACE, &mut send_task).await.is_err() {
+        warn!(
+            "Connection {}: messages not read before the shutdown, dropped",
+            id
+        );
+        send_task.abort();
+    }
+}
@@ -0,0 +1,49 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::{BuildHasher, Hash, RandomState},
+};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+const SHARDS: usize = 64;
+
+/// HashMap split in shards with their own lock, operations on different keys
+/// rarely wait for each other
+pub struct ShardedMap<K, V> {
+    shards: Vec<Mutex<HashMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        ShardedMap {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    /// Lock the shard holding `key`, the other shards stay available
+    pub async fn lock<Q>(&self, key: &Q) -> MutexGuard<'_, HashMap<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + ?Sized,
+    {
+        let shard = self.hasher.hash_one(key) as usize % SHARDS;
+        self.shards[shard].lock().await
+    }
+
+    /// Snapshot of the keys, shard by shard
+    pub async fn keys(&self) -> Vec<K>
+    where
+        K: Clone,
+    {
+        let mut keys = vec![];
+        for shard in &self.shards {
+            keys.extend(shard.lock().await.keys().cloned());
+        }
+        keys
+    }
+}
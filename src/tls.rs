@@ -0,0 +1,162 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::{
+    crypto::{aws_lc_rs, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    RootCertStore,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// how often the certificate files are checked for a renewal
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Certificate resolver that can swap its certificate while the server is running.
+/// New handshakes use the latest loaded certificate, established connections are not affected.
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    cert_path: String,
+    key_path: String,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(cert_path: &str, key_path: &str) -> anyhow::Result<Self> {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+        Ok(ReloadingCertResolver {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: RwLock::new(Arc::new(certified_key)),
+        })
+    }
+
+    /// Reload the certificate and key from disk.
+    /// On error, the previous certificate is kept.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        info!("TLS certificate reloaded from {}", self.cert_path);
+        Ok(())
+    }
+
+    fn files_modified(&self) -> Option<SystemTime> {
+        let cert = fs::metadata(&self.cert_path)
+            .and_then(|m| m.modified())
+            .ok()?;
+        let key = fs::metadata(&self.key_path)
+            .and_then(|m| m.modified())
+            .ok()?;
+        Some(cert.max(key))
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &str, key_path: &str) -> anyhow::Result<CertifiedKey> {
+    // load the whole chain, not only the leaf certificate
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .context("no certificate found")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("invalid certificate")?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", cert_path);
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).context("no key found")?;
+
+    let provider = CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(aws_lc_rs::default_provider()));
+    CertifiedKey::from_der(certs, key, &provider).context("certificate and key do not match")
+}
+
+/// Reload the certificate on SIGHUP, or when the files on disk change (certbot renewal),
+/// until `shutdown` is cancelled.
+pub fn spawn_cert_reloader(
+    resolver: Arc<ReloadingCertResolver>,
+    shutdown: CancellationToken,
+    tasks: &TaskTracker,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+
+    tasks.spawn(async move {
+        let mut last_modified = resolver.files_modified();
+        let mut interval = tokio::time::interval(CERT_POLL_INTERVAL);
+
+        loop {
+            let forced = tokio::select! {
+                _ = hangup.recv() => true,
+                _ = interval.tick() => false,
+                _ = shutdown.cancelled() => return,
+            };
+
+            let modified = resolver.files_modified();
+            if !forced && modified == last_modified {
+                continue;
+            }
+
+            // when the reload fails (e.g. key written before the certificate),
+            // keep the old timestamp so the next tick tries again
+            match resolver.reload() {
+                Ok(()) => last_modified = modified,
+                Err(e) => error!("TLS certificate reload failed: {:?}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Verifier for optional client certificates signed by the given CA.
+/// Clients without a certificate (browsers) are still accepted.
+pub fn client_verifier(ca_path: &str) -> anyhow::Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path).context("no client CA found")? {
+        roots.add(cert.context("invalid client CA")?)?;
+    }
+
+    Ok(WebPkiClientVerifier::builder(Arc::new(roots))
+        .allow_unauthenticated()
+        .build()?)
+}
+
+/// Subject (CN) of an already verified client certificate,
+/// if it belongs to a trusted publisher (every verified subject when `trusted` is empty).
+pub fn trusted_publisher(certs: &[CertificateDer<'_>], trusted: &[String]) -> Option<String> {
+    let (_, cert) = match X509Certificate::from_der(certs.first()?) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Unreadable client certificate: {}", e);
+            return None;
+        }
+    };
+    let subject = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string())
+        .unwrap_or_else(|| cert.subject().to_string());
+
+    if trusted.is_empty() || trusted.contains(&subject) {
+        Some(subject)
+    } else {
+        warn!(
+            "Client certificate '{}' is not a trusted publisher",
+            subject
+        );
+        None
+    }
+}
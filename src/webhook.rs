@@ -0,0 +1,244 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
+
+use crate::{
+    auth,
+    config_loader::{RoomConfig, WebhookConfig, WebhookEvent},
+};
+
+/// hex HMAC-SHA256 of the body, with the `webhookSecret` of the room
+pub const SIGNATURE_HEADER: &str = "X-Chaline-Signature";
+/// events of a room posted together at most
+const BATCH_SIZE: usize = 100;
+/// how long a batch waits for more events
+const BATCH_DELAY: Duration = Duration::from_millis(200);
+const POST_ATTEMPTS: u32 = 5;
+const POST_RETRY_DELAY: Duration = Duration::from_millis(500);
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+/// events of a room waiting to be posted at most, the oldest are dropped over it
+const QUEUE_SIZE: usize = 10_000;
+
+/// Events of a room waiting for its task
+struct EventQueue {
+    events: Mutex<VecDeque<Value>>,
+    capacity: usize,
+    ready: Notify,
+}
+
+impl EventQueue {
+    fn new(capacity: usize) -> Self {
+        EventQueue {
+            events: Mutex::new(VecDeque::new()),
+            capacity,
+            ready: Notify::new(),
+        }
+    }
+
+    /// Queue an event, dropping the oldest one when full (webhook down or too slow)
+    fn push(&self, room: &str, event: Value) {
+        {
+            let mut events = self.events.lock().unwrap();
+            if events.len() >= self.capacity {
+                events.pop_front();
+                warn!("Webhook queue of {} full, oldest event dropped", room);
+            }
+            events.push_back(event);
+        }
+        self.ready.notify_one();
+    }
+
+    /// The oldest queued events, `max` at most
+    fn take(&self, max: usize) -> Vec<Value> {
+        let mut events = self.events.lock().unwrap();
+        let count = events.len().min(max);
+        events.drain(..count).collect()
+    }
+}
+
+struct RoomWebhook {
+    events: Vec<WebhookEvent>,
+    queue: Arc<EventQueue>,
+}
+
+/// Events of the rooms with a `webhookURL`, posted by one task per room
+#[derive(Default)]
+pub struct Webhooks {
+    rooms: HashMap<String, RoomWebhook>,
+    stop: CancellationToken,
+    tasks: TaskTracker,
+}
+
+fn event_json(
+    event: WebhookEvent,
+    room_group: &str,
+    client_id: Option<u64>,
+    msg: Option<&Message>,
+) -> Value {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut v = json!({"event": event.name(), "roomGroup": room_group, "time": time});
+    if let Some(id) = client_id {
+        v["client"] = json!(id);
+    }
+    match msg {
+        Some(Message::Text(txt)) => v["text"] = json!(txt.as_str()),
+        Some(Message::Binary(data)) => v["binary"] = json!(hex::encode(data)),
+        _ => {}
+    }
+    v
+}
+
+impl Webhooks {
+    pub fn start(room_configs: &HashMap<String, RoomConfig>) -> Self {
+        let mut webhooks = Webhooks::default();
+        for conf in room_configs.values() {
+            let Some(webhook) = &conf.webhook else {
+                continue;
+            };
+            let queue = Arc::new(EventQueue::new(QUEUE_SIZE));
+            webhooks.tasks.spawn(forward(
+                conf.prefix.clone(),
+                webhook.clone(),
+                Arc::clone(&queue),
+                webhooks.stop.clone(),
+            ));
+            info!("Events of {} posted to {}", conf.prefix, webhook.url);
+            webhooks.rooms.insert(
+                conf.prefix.clone(),
+                RoomWebhook {
+                    events: webhook.events.clone(),
+                    queue,
+                },
+            );
+        }
+        webhooks
+    }
+
+    /// Queue an event of a room group, if its room posts this kind of event
+    pub fn notify(
+        &self,
+        event: WebhookEvent,
+        room_group: &str,
+        client_id: Option<u64>,
+        msg: Option<&Message>,
+    ) {
+        let room = room_group.split('/').next().unwrap_or_default();
+        if let Some(webhook) = self.rooms.get(room) {
+            if webhook.events.contains(&event) {
+                let json = event_json(event, room_group, client_id, msg);
+                webhook.queue.push(room, json);
+            }
+        }
+    }
+
+    /// Post the queued events, then stop
+    pub async fn stop(&self) {
+        self.stop.cancel();
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+}
+
+/// Batch the events of a room until stopped
+async fn forward(
+    room: String,
+    conf: WebhookConfig,
+    queue: Arc<EventQueue>,
+    stop: CancellationToken,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(POST_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    loop {
+        let mut batch = queue.take(BATCH_SIZE);
+        if batch.is_empty() {
+            tokio::select! {
+                _ = queue.ready.notified() => continue,
+                _ = stop.cancelled() => break,
+            }
+        }
+        let flush = tokio::time::sleep(BATCH_DELAY);
+        tokio::pin!(flush);
+        while batch.len() < BATCH_SIZE {
+            tokio::select! {
+                _ = queue.ready.notified() => batch.extend(queue.take(BATCH_SIZE - batch.len())),
+                _ = &mut flush => break,
+                _ = stop.cancelled() => break,
+            }
+        }
+        post(&client, &room, &conf, &batch).await;
+    }
+
+    // events queued before the stop
+    loop {
+        let batch = queue.take(BATCH_SIZE);
+        if batch.is_empty() {
+            break;
+        }
+        post(&client, &room, &conf, &batch).await;
+    }
+}
+
+/// Post a batch, retried with backoff before being dropped
+async fn post(client: &reqwest::Client, room: &str, conf: &WebhookConfig, events: &[Value]) {
+    let body = json!({"room": room, "events": events}).to_string();
+    let mut delay = POST_RETRY_DELAY;
+    for attempt in 1..=POST_ATTEMPTS {
+        let mut request = client
+            .post(&conf.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(secret) = &conf.secret {
+            request = request.header(SIGNATURE_HEADER, auth::sign(secret, body.as_bytes()));
+        }
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() => return,
+            Ok(resp) => warn!(
+                "Webhook of {} answered {} ({}/{})",
+                room,
+                resp.status(),
+                attempt,
+                POST_ATTEMPTS
+            ),
+            Err(e) => warn!(
+                "Webhook of {} unreachable ({}/{}): {:?}",
+                room, attempt, POST_ATTEMPTS, e
+            ),
+        }
+        if attempt < POST_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+    error!("{} events of {} dropped", events.len(), room);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_events_are_dropped_when_full() {
+        let queue = EventQueue::new(3);
+        for id in 0..5 {
+            queue.push("micasend", json!(id));
+        }
+        assert_eq!(queue.take(2), vec![json!(2), json!(3)]);
+        queue.push("micasend", json!(5));
+        assert_eq!(queue.take(BATCH_SIZE), vec![json!(4), json!(5)]);
+        assert!(queue.take(BATCH_SIZE).is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+//! In-process server and mock backend for the end-to-end tests
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chaline_websocket::{
+    config_loader::{ListenerConfig, RoomConfig, RoomKind},
+    ServerBuilder, ServerHandle,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::{frame::coding::CloseCode, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+pub type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `fetchURL` of the group rooms: answers `yes` for the groups it knows
+#[derive(Clone)]
+pub struct MockBackend {
+    pub url: String,
+    groups: Arc<Mutex<HashSet<String>>>,
+    /// answers 503 while set, counting them
+    unavailable: Arc<Mutex<Option<usize>>>,
+}
+
+impl MockBackend {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/group?id=", listener.local_addr().unwrap());
+        let groups = Arc::new(Mutex::new(HashSet::new()));
+
+        let unavailable = Arc::new(Mutex::new(None));
+
+        let known = Arc::clone(&groups);
+        let failing = Arc::clone(&unavailable);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let known = Arc::clone(&known);
+                let failing = Arc::clone(&failing);
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = stream.read(&mut buf).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    // GET /group?id=<group> HTTP/1.1
+                    let group = request
+                        .split_whitespace()
+                        .nth(1)
+                        .and_then(|path| path.split_once("id="))
+                        .map(|(_, group)| group.to_string())
+                        .unwrap_or_default();
+                    let body = match known.lock().unwrap().contains(&group) {
+                        true => "yes",
+                        false => "no",
+                    };
+                    let status = match failing.lock().unwrap().as_mut() {
+                        Some(count) => {
+                            *count += 1;
+                            "503 Service Unavailable"
+                        }
+                        None => "200 OK",
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        MockBackend {
+            url,
+            groups,
+            unavailable,
+        }
+    }
+
+    /// Answer 503 to every request until `recover`
+    pub fn fail(&self) {
+        *self.unavailable.lock().unwrap() = Some(0);
+    }
+
+    /// Stop failing, returns the number of 503 answered
+    pub fn recover(&self) -> usize {
+        self.unavailable.lock().unwrap().take().unwrap_or(0)
+    }
+
+    pub fn add_group(&self, group: &str) {
+        self.groups.lock().unwrap().insert(group.to_string());
+    }
+
+    pub fn remove_group(&self, group: &str) {
+        self.groups.lock().unwrap().remove(group);
+    }
+}
+
+/// A webhook POST: its signature header and json body
+#[derive(Clone, Debug)]
+pub struct WebhookPost {
+    pub signature: Option<String>,
+    pub body: String,
+}
+
+/// `webhookURL` of the rooms, records the posts
+#[derive(Clone)]
+pub struct WebhookStub {
+    pub url: String,
+    posts: Arc<Mutex<Vec<WebhookPost>>>,
+    /// posts answered with an error before accepting them
+    failures: Arc<AtomicUsize>,
+}
+
+/// Headers and body of an HTTP request
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String)> {
+    let mut data = vec![];
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&data).to_string();
+        let Some((head, body)) = text.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let length = head
+            .lines()
+            .find_map(|l| {
+                l.to_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|v| v.trim().to_string())
+            })
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        if body.len() >= length {
+            return Some((head.to_string(), body.to_string()));
+        }
+    }
+}
+
+impl WebhookStub {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let stub = WebhookStub {
+            url,
+            posts: Arc::default(),
+            failures: Arc::default(),
+        };
+
+        let server = stub.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let server = server.clone();
+                tokio::spawn(async move {
+                    let Some((head, body)) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let failed = server
+                        .failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                    let status = match failed {
+                        true => "500 Internal Server Error",
+                        false => "200 OK",
+                    };
+                    let signature = head.lines().find_map(|l| {
+                        l.to_lowercase()
+                            .strip_prefix("x-chaline-signature:")
+                            .map(|v| v.trim().to_string())
+                    });
+                    server
+                        .posts
+                        .lock()
+                        .unwrap()
+                        .push(WebhookPost { signature, body });
+                    let response = format!(
+                        "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        status
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        stub
+    }
+
+    /// Answer the next posts with an error
+    pub fn fail(&self, count: usize) {
+        self.failures.store(count, Ordering::SeqCst);
+    }
+
+    pub fn posts(&self) -> Vec<WebhookPost> {
+        self.posts.lock().unwrap().clone()
+    }
+
+    /// Events of the posts received, waiting for `count` of them
+    pub async fn events(&self, count: usize) -> Vec<serde_json::Value> {
+        let received = || {
+            self.posts()
+                .iter()
+                .flat_map(|post| {
+                    let v: serde_json::Value = serde_json::from_str(&post.body).unwrap();
+                    v["events"].as_array().unwrap().clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        let waited = tokio::time::timeout(TIMEOUT, async {
+            while received().len() < count {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(
+            waited.is_ok(),
+            "expected {} events, got {:?}",
+            count,
+            received()
+        );
+        received()
+    }
+}
+
+/// Rooms of the tests:
+/// - `micasend`: broadcast, `ping` is answered `pong`
+/// - `nokertu`: groups checked against the backend, `join` and `turn` allowed
+pub fn room_configs(backend: &MockBackend) -> HashMap<String, RoomConfig> {
+    let mut micasend = RoomConfig::new("micasend", RoomKind::Broadcast);
+    micasend
+        .message_map
+        .insert("ping".to_string(), "pong".to_string());
+
+    let mut nokertu = RoomConfig::new("nokertu", RoomKind::Group(backend.url.clone()));
+    nokertu.authorized_messages = vec!["join".to_string(), "turn".to_string()];
+
+    [micasend, nokertu]
+        .into_iter()
+        .map(|conf| (conf.prefix.clone(), conf))
+        .collect()
+}
+
+/// Start a server on a free port, returns its `ws://` url
+pub async fn start_server(room_configs: HashMap<String, RoomConfig>) -> (String, ServerHandle) {
+    start_with(ServerBuilder::new(), room_configs).await
+}
+
+pub async fn start_with(
+    builder: ServerBuilder,
+    room_configs: HashMap<String, RoomConfig>,
+) -> (String, ServerHandle) {
+    let builder = room_configs
+        .into_values()
+        .fold(builder, |builder, conf| builder.room(conf))
+        .listener(ListenerConfig::plain("127.0.0.1:0"));
+    let server = builder.start().await.unwrap();
+    (format!("ws://{}", server.local_addrs()[0]), server)
+}
+
+pub async fn connect(url: &str) -> Client {
+    connect_async(url).await.unwrap().0
+}
+
+pub async fn send(client: &mut Client, txt: &str) {
+    client.send(Message::text(txt)).await.unwrap();
+}
+
+/// Next text message, panics on timeout or close
+pub async fn recv_text(client: &mut Client) -> String {
+    match tokio::time::timeout(TIMEOUT, client.next()).await {
+        Ok(Some(Ok(Message::Text(txt)))) => txt.to_string(),
+        other => panic!("expected a text message, got {:?}", other),
+    }
+}
+
+/// Waits for the server to close the connection
+pub async fn assert_closed(client: &mut Client) {
+    match tokio::time::timeout(TIMEOUT, client.next()).await {
+        Ok(None | Some(Ok(Message::Close(_))) | Some(Err(_))) => {}
+        Ok(Some(Ok(msg))) => panic!("expected the connection to close, got {:?}", msg),
+        Err(_) => panic!("connection still open"),
+    }
+}
+
+/// Waits for a close frame with this code
+pub async fn assert_close_code(client: &mut Client, code: CloseCode) {
+    match tokio::time::timeout(TIMEOUT, client.next()).await {
+        Ok(Some(Ok(Message::Close(Some(frame))))) => assert_eq!(frame.code, code),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+}
@@ -0,0 +1,202 @@
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chaline_websocket::{
+    config_loader::{BackplaneConfig, GlobalConfig, ListenerConfig, RoomConfig, RoomKind},
+    room_handler::{Delivery, RoomEvent, RoomHandler, Verdict},
+    RoomGroup, ServerBuilder,
+};
+use common::{assert_closed, connect, recv_text, room_configs, send, start_with, MockBackend};
+use futures::{future::BoxFuture, StreamExt};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::{frame::coding::CloseCode, Message},
+};
+
+#[tokio::test]
+async fn hooks_see_joins_and_broadcasts() {
+    let backend = MockBackend::start().await;
+    let events = Arc::new(Mutex::new(vec![]));
+    let (joins, broadcasts) = (Arc::clone(&events), Arc::clone(&events));
+    let builder = ServerBuilder::new()
+        .on_join(move |e| joins.lock().unwrap().push(format!("join {}", e.room_group)))
+        .on_broadcast(move |e| {
+            broadcasts
+                .lock()
+                .unwrap()
+                .push(format!("broadcast {} {}", e.room_group, e.message))
+        });
+    let (url, _server) = start_with(builder, room_configs(&backend)).await;
+
+    let mut alice = connect(&url).await;
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "join micasend",
+            "broadcast micasend pong",
+            "broadcast micasend pong"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn shutdown_closes_the_connections() {
+    let backend = MockBackend::start().await;
+    let (url, server) = start_with(ServerBuilder::new(), room_configs(&backend)).await;
+    let mut alice = connect(&url).await;
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+
+    server.shutdown().await;
+
+    match alice.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+    assert!(connect_async(&url).await.is_err());
+}
+
+#[tokio::test]
+async fn clients_not_reading_dont_hold_the_shutdown() {
+    let backend = MockBackend::start().await;
+    let mut configs = room_configs(&backend);
+    let chat = RoomConfig::new("chat", RoomKind::Broadcast);
+    configs.insert("chat".to_string(), chat);
+    let (url, server) = start_with(ServerBuilder::new(), configs).await;
+
+    // subscribed by its path, never reads what the room sends
+    let _stalled = connect(&format!("{}/chat", url)).await;
+    let mut alice = connect(&url).await;
+    let message = format!("chat:{}", "x".repeat(60 * 1024));
+    for _ in 0..400 {
+        send(&mut alice, &message).await;
+    }
+    for _ in 0..400 {
+        recv_text(&mut alice).await;
+    }
+
+    let stopped = tokio::time::timeout(Duration::from_secs(10), server.shutdown());
+    assert!(stopped.await.is_ok());
+}
+
+/// Moves are shouted to the game, resigning is refused
+#[derive(Default)]
+struct Chess {
+    joined: Arc<Mutex<Vec<String>>>,
+}
+
+impl RoomHandler for Chess {
+    fn on_group_create<'a>(&'a self, rg: &'a RoomGroup) -> BoxFuture<'a, bool> {
+        Box::pin(async move { rg.group.as_deref().is_some_and(|g| g != "closed") })
+    }
+
+    fn on_join<'a>(&'a self, event: &'a RoomEvent<'a>) -> BoxFuture<'a, Verdict> {
+        let rg = &event.room_group.full_roomgroup;
+        self.joined.lock().unwrap().push(rg.clone());
+        let welcome = format!("welcome to {}", rg);
+        Box::pin(async move { Verdict::Deliver(vec![Delivery::Client(Message::text(welcome))]) })
+    }
+
+    fn on_message<'a>(&'a self, event: &'a RoomEvent<'a>, msg: Message) -> BoxFuture<'a, Verdict> {
+        let rg = event.room_group.full_roomgroup.clone();
+        Box::pin(async move {
+            let txt = msg.to_text().unwrap_or_default();
+            if txt == "resign" {
+                return Verdict::Reject;
+            }
+            Verdict::Deliver(vec![
+                Delivery::Group(rg, Message::text(txt.to_uppercase())),
+                Delivery::Client(Message::text("played")),
+            ])
+        })
+    }
+
+    fn on_leave<'a>(&'a self, event: &'a RoomEvent<'a>) -> BoxFuture<'a, Vec<Delivery>> {
+        let rg = event.room_group.full_roomgroup.clone();
+        Box::pin(async move { vec![Delivery::Group(rg, Message::text("opponent left"))] })
+    }
+}
+
+#[tokio::test]
+async fn custom_room_kinds_rewrite_and_reject() {
+    let backend = MockBackend::start().await;
+    let mut configs = room_configs(&backend);
+    let chess = RoomConfig::new("chess", RoomKind::Custom("chess".to_string()));
+    configs.insert("chess".to_string(), chess);
+    let chess = Chess::default();
+    let joined = Arc::clone(&chess.joined);
+    let (url, _server) = start_with(ServerBuilder::new().room_kind("chess", chess), configs).await;
+
+    let mut alice = connect(&url).await;
+    send(&mut alice, "chess/1:e4").await;
+    assert_eq!(recv_text(&mut alice).await, "welcome to chess/1");
+    assert_eq!(recv_text(&mut alice).await, "E4");
+    assert_eq!(recv_text(&mut alice).await, "played");
+
+    let mut bob = connect(&url).await;
+    send(&mut bob, "chess/1:e5").await;
+    assert_eq!(recv_text(&mut bob).await, "welcome to chess/1");
+    assert_eq!(recv_text(&mut alice).await, "E5");
+    assert_eq!(recv_text(&mut bob).await, "E5");
+    assert_eq!(recv_text(&mut bob).await, "played");
+
+    send(&mut bob, "~chess/1").await;
+    assert_eq!(recv_text(&mut alice).await, "opponent left");
+
+    // the group is refused by the room, then a message
+    send(&mut bob, "chess/closed:e5").await;
+    assert_closed(&mut bob).await;
+    // the room only sees joins of validated groups
+    assert_eq!(*joined.lock().unwrap(), vec!["chess/1", "chess/1"]);
+    send(&mut alice, "chess/1:resign").await;
+    assert_closed(&mut alice).await;
+}
+
+#[tokio::test]
+async fn unknown_room_kinds_fail_the_start() {
+    let chess = RoomConfig::new("chess", RoomKind::Custom("chess".to_string()));
+    assert!(ServerBuilder::new().room(chess).start().await.is_err());
+}
+
+#[tokio::test]
+async fn failed_starts_leave_nothing_running() {
+    let backplane = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = GlobalConfig {
+        backplane: Some(BackplaneConfig {
+            address: backplane.to_string(),
+            peers: vec![],
+            token: "cluster secret".to_string(),
+        }),
+        ..Default::default()
+    };
+    let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let failed = ServerBuilder::new()
+        .config(config.clone())
+        .listener(ListenerConfig::plain(
+            &taken.local_addr().unwrap().to_string(),
+        ))
+        .start()
+        .await;
+    assert!(failed.is_err());
+
+    // the backplane address is still free
+    let server = ServerBuilder::new()
+        .config(config)
+        .listener(ListenerConfig::plain("127.0.0.1:0"))
+        .start()
+        .await
+        .unwrap();
+    server.shutdown().await;
+}
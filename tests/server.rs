@@ -0,0 +1,483 @@
+mod common;
+
+use chaline_websocket::{
+    config_loader::{
+        AdminConfig, GlobalConfig, ListenerConfig, OfflineConfig, RoomConfig, RoomKind,
+    },
+    ServerBuilder, ServerHandle,
+};
+use common::{
+    assert_close_code, assert_closed, connect, recv_text, room_configs, send, start_server,
+    start_with, MockBackend,
+};
+use futures::SinkExt;
+use std::{sync::Arc, time::Duration};
+use tokio::{io::AsyncReadExt, net::TcpStream};
+use tokio_rustls::{
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::StatusCode,
+        protocol::{frame::coding::CloseCode, Message},
+        Error,
+    },
+};
+
+#[tokio::test]
+async fn broadcast_reaches_every_member() {
+    let backend = MockBackend::start().await;
+    let (url, _server) = start_server(room_configs(&backend)).await;
+    let mut alice = connect(&url).await;
+    let mut bob = connect(&url).await;
+
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+    send(&mut bob, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+    assert_eq!(recv_text(&mut bob).await, "pong");
+}
+
+#[tokio::test]
+async fn members_of_a_group_receive_its_messages() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    let (url, _server) = start_server(room_configs(&backend)).await;
+    let mut alice = connect(&url).await;
+    let mut bob = connect(&url).await;
+
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+    send(&mut bob, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+    assert_eq!(recv_text(&mut bob).await, "join");
+
+    // path subscription
+    let mut carol = connect(&format!("{}/nokertu/42", url)).await;
+    send(&mut bob, "nokertu/42:turn").await;
+    assert_eq!(recv_text(&mut carol).await, "turn");
+}
+
+#[tokio::test]
+async fn joining_an_invalid_group_closes_the_connection() {
+    let backend = MockBackend::start().await;
+    let (url, _server) = start_server(room_configs(&backend)).await;
+    let mut alice = connect(&url).await;
+
+    send(&mut alice, "nokertu/404:join").await;
+    assert_closed(&mut alice).await;
+}
+
+#[tokio::test]
+async fn unauthorized_messages_close_the_connection() {
+    let backend = MockBackend::start().await;
+    let (url, _server) = start_server(room_configs(&backend)).await;
+
+    let mut alice = connect(&url).await;
+    send(&mut alice, "micasend:hello").await;
+    assert_closed(&mut alice).await;
+
+    let mut bob = connect(&url).await;
+    send(&mut bob, "unknown:ping").await;
+    assert_closed(&mut bob).await;
+}
+
+#[tokio::test]
+async fn destroyed_groups_disconnect_their_members() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    let mut configs = room_configs(&backend);
+    configs.get_mut("nokertu").unwrap().anonymous_destroy = true;
+    let (url, _server) = start_server(configs).await;
+    let mut alice = connect(&url).await;
+    let mut bob = connect(&url).await;
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+
+    // the group still exists for the backend
+    send(&mut bob, "-nokertu/42").await;
+    assert_closed(&mut bob).await;
+    send(&mut alice, "nokertu/42:turn").await;
+    assert_eq!(recv_text(&mut alice).await, "turn");
+
+    backend.remove_group("42");
+    let mut bob = connect(&url).await;
+    send(&mut bob, "-nokertu/42").await;
+    assert_closed(&mut alice).await;
+    assert_closed(&mut bob).await;
+}
+
+#[tokio::test]
+async fn anonymous_clients_cant_destroy_groups_by_default() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    let (url, _server) = start_server(room_configs(&backend)).await;
+    let mut alice = connect(&url).await;
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+
+    // even once the backend has deleted it
+    backend.remove_group("42");
+    let mut bob = connect(&url).await;
+    send(&mut bob, "-nokertu/42").await;
+    assert_closed(&mut bob).await;
+    send(&mut alice, "nokertu/42:turn").await;
+    assert_eq!(recv_text(&mut alice).await, "turn");
+}
+
+#[tokio::test]
+async fn handshakes_from_other_origins_are_forbidden() {
+    let backend = MockBackend::start().await;
+    let config = GlobalConfig {
+        allowed_origins: vec!["https://nokertu.magictintin.fr".to_string()],
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new().config(config);
+    let (url, _server) = start_with(builder, room_configs(&backend)).await;
+
+    let with_origin = |origin: &str| {
+        let mut request = url.as_str().into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Origin", origin.parse().unwrap());
+        request
+    };
+    match connect_async(with_origin("https://evil.example")).await {
+        Err(Error::Http(response)) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+        other => panic!("expected a 403, got {:?}", other.map(|(_, r)| r)),
+    }
+    assert!(connect_async(with_origin("https://nokertu.magictintin.fr"))
+        .await
+        .is_ok());
+}
+
+#[tokio::test]
+async fn messages_over_the_room_limit_close_with_1009() {
+    let backend = MockBackend::start().await;
+    let mut configs = room_configs(&backend);
+    configs.get_mut("micasend").unwrap().max_message_size = Some(16);
+    let (url, _server) = start_server(configs).await;
+
+    // checked by the room
+    let mut alice = connect(&url).await;
+    send(&mut alice, &format!("micasend:{}", "x".repeat(32))).await;
+    assert_close_code(&mut alice, CloseCode::Size).await;
+
+    // checked while reading, for a connection subscribed by its path:
+    // binary frames would be refused by the room with another code
+    let mut bob = connect(&format!("{}/micasend", url)).await;
+    send(&mut bob, "ping").await;
+    assert_eq!(recv_text(&mut bob).await, "pong");
+    bob.send(Message::binary(vec![0; 32])).await.unwrap();
+    assert_close_code(&mut bob, CloseCode::Size).await;
+}
+
+#[tokio::test]
+async fn connections_over_the_limit_are_refused() {
+    let backend = MockBackend::start().await;
+    let config = GlobalConfig {
+        max_connections_per_ip: Some(1),
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new().config(config);
+    let (url, _server) = start_with(builder, room_configs(&backend)).await;
+
+    let mut alice = connect(&url).await;
+    // refused as soon as accepted, before reading any request
+    let mut socket = TcpStream::connect(url.trim_start_matches("ws://"))
+        .await
+        .unwrap();
+    let mut answer = String::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), socket.read_to_string(&mut answer));
+    assert!(read.await.is_ok());
+    assert!(answer.starts_with("HTTP/1.1 429"), "{}", answer);
+
+    // the place is given back when the connection ends
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+    drop(alice);
+    let mut accepted = None;
+    for _ in 0..50 {
+        if let Ok((client, _)) = connect_async(&url).await {
+            accepted = Some(client);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(accepted.is_some());
+}
+
+#[tokio::test]
+async fn revalidation_keeps_groups_while_the_backend_fails() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    let mut configs = room_configs(&backend);
+    configs.get_mut("nokertu").unwrap().revalidate_interval = Some(Duration::from_millis(100));
+    let (url, _server) = start_server(configs).await;
+    let mut alice = connect(&url).await;
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+
+    backend.fail();
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    assert!(backend.recover() > 0);
+    send(&mut alice, "nokertu/42:turn").await;
+    assert_eq!(recv_text(&mut alice).await, "turn");
+
+    // once the backend answers again, the deleted group is closed
+    backend.remove_group("42");
+    assert_eq!(recv_text(&mut alice).await, "group closed");
+    assert_closed(&mut alice).await;
+}
+
+#[tokio::test]
+async fn messages_are_kept_for_the_offline_recipient() {
+    let backend = MockBackend::start().await;
+    backend.add_group("bob");
+    let dir = std::env::temp_dir().join(format!("chaline-e2e-offline-{}", std::process::id()));
+    let mut jirsend = RoomConfig::new("jirsend", RoomKind::Individual(backend.url.clone()));
+    jirsend.authorized_messages = vec!["hi".to_string()];
+    jirsend.offline = Some(OfflineConfig {
+        dir: dir.clone(),
+        max_messages: 10,
+        max_age: None,
+    });
+    let mut configs = room_configs(&backend);
+    configs.insert("jirsend".to_string(), jirsend);
+    let (url, _server) = start_server(configs).await;
+    let file = dir
+        .join("jirsend")
+        .join(format!("{}.jsonl", hex::encode("bob")));
+
+    // two senders connected, the recipient is not
+    let mut alice = connect(&url).await;
+    let mut carol = connect(&url).await;
+    send(&mut carol, "jirsend/bob:hi").await;
+    assert_eq!(recv_text(&mut carol).await, "hi");
+    send(&mut alice, "jirsend/bob:hi").await;
+    assert_eq!(recv_text(&mut alice).await, "hi");
+    assert_eq!(recv_text(&mut carol).await, "hi");
+    assert!(file.exists());
+
+    let mut bob = connect(&format!("{}/jirsend/bob", url)).await;
+    assert_eq!(recv_text(&mut bob).await, "hi");
+    assert_eq!(recv_text(&mut bob).await, "hi");
+    let marker = recv_text(&mut bob).await;
+    assert!(marker.starts_with("+jirsend/bob "));
+
+    // only the recipient acknowledges them
+    send(&mut carol, &marker).await;
+    assert_closed(&mut carol).await;
+    send(&mut bob, &marker).await;
+    send(&mut bob, "jirsend/bob:hi").await;
+    assert_eq!(recv_text(&mut bob).await, "hi");
+    assert!(!file.exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn path_messages_may_start_like_commands() {
+    let backend = MockBackend::start().await;
+    backend.add_group("7");
+    let mut configs = room_configs(&backend);
+    let clavardons = RoomConfig::new("clavardons", RoomKind::Group(backend.url.clone()));
+    configs.insert("clavardons".to_string(), clavardons);
+    let (url, _server) = start_server(configs).await;
+
+    let mut alice = connect(&format!("{}/clavardons/7", url)).await;
+    let mut bob = connect(&format!("{}/clavardons/7", url)).await;
+    // contents for the group of the path, not acks or leaves
+    for txt in ["+1 for that", "~ see you"] {
+        send(&mut alice, txt).await;
+        assert_eq!(recv_text(&mut alice).await, txt);
+        assert_eq!(recv_text(&mut bob).await, txt);
+    }
+
+    // a room group is still a command, alice misses the next message
+    send(&mut alice, "~clavardons/7").await;
+    send(&mut bob, "still here").await;
+    assert_eq!(recv_text(&mut bob).await, "still here");
+    send(&mut alice, "clavardons/7:back").await;
+    assert_eq!(recv_text(&mut alice).await, "back");
+    assert_eq!(recv_text(&mut bob).await, "back");
+}
+
+#[tokio::test]
+async fn silent_sockets_are_closed_after_the_upgrade_timeout() {
+    let backend = MockBackend::start().await;
+    let config = GlobalConfig {
+        upgrade_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new().config(config);
+    let (url, server) = start_with(builder, room_configs(&backend)).await;
+
+    // connected, the upgrade request is never sent
+    let mut socket = TcpStream::connect(url.trim_start_matches("ws://"))
+        .await
+        .unwrap();
+    let mut answer = vec![];
+    let read = tokio::time::timeout(Duration::from_secs(5), socket.read_to_end(&mut answer));
+    assert!(read.await.is_ok_and(|n| n.is_ok_and(|n| n == 0)));
+    assert_eq!(server.aborted_handshakes(), 1);
+}
+
+#[tokio::test]
+async fn connections_without_a_first_message_are_closed_with_1008() {
+    let backend = MockBackend::start().await;
+    let config = GlobalConfig {
+        first_message_timeout: Some(Duration::from_millis(200)),
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new().config(config);
+    let (url, _server) = start_with(builder, room_configs(&backend)).await;
+
+    let mut alice = connect(&url).await;
+    assert_close_code(&mut alice, CloseCode::Policy).await;
+    // a first message in time keeps the connection
+    let mut bob = connect(&url).await;
+    send(&mut bob, "micasend:ping").await;
+    assert_eq!(recv_text(&mut bob).await, "pong");
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    send(&mut bob, "micasend:ping").await;
+    assert_eq!(recv_text(&mut bob).await, "pong");
+}
+
+/// Server with the admin API on a free port, returns its url and the one of the API
+async fn start_with_admin(backend: &MockBackend) -> (String, String, ServerHandle) {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = GlobalConfig {
+        admin: Some(AdminConfig {
+            address: format!("127.0.0.1:{}", port),
+            token: "admin secret".to_string(),
+        }),
+        ..Default::default()
+    };
+    let builder = ServerBuilder::new().config(config);
+    let (url, server) = start_with(builder, room_configs(backend)).await;
+    (url, format!("http://127.0.0.1:{}", port), server)
+}
+
+/// POST to the admin API once it listens, returns the status and the body
+async fn admin_post(url: &str, token: Option<&str>, body: &str) -> (u16, String) {
+    let client = reqwest::Client::new();
+    for _ in 0..50 {
+        let mut request = client.post(url).body(body.to_string());
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Ok(resp) = request.send().await {
+            return (resp.status().as_u16(), resp.text().await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("admin API not listening on {}", url);
+}
+
+#[tokio::test]
+async fn admin_requests_need_the_token() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    let (url, admin, _server) = start_with_admin(&backend).await;
+    let mut alice = connect(&url).await;
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+
+    let close = format!("{}/groups/nokertu/42/close", admin);
+    assert_eq!(admin_post(&close, None, "").await.0, 401);
+    assert_eq!(admin_post(&close, Some("wrong"), "").await.0, 401);
+    let unknown = format!("{}/groups/nokertu/43/close", admin);
+    assert_eq!(admin_post(&unknown, Some("wrong"), "").await.0, 401);
+
+    // nothing was closed
+    send(&mut alice, "nokertu/42:turn").await;
+    assert_eq!(recv_text(&mut alice).await, "turn");
+}
+
+#[tokio::test]
+async fn admin_requests_close_known_groups() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    backend.add_group("43");
+    let (url, admin, _server) = start_with_admin(&backend).await;
+    let token = Some("admin secret");
+
+    for path in ["unknown/1", "micasend", "nokertu/42/members"] {
+        let unknown = format!("{}/groups/{}/close", admin, path);
+        assert_eq!(admin_post(&unknown, token, "").await.0, 404, "{}", path);
+    }
+
+    let mut alice = connect(&url).await;
+    let mut bob = connect(&url).await;
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+    send(&mut bob, "nokertu/43:join").await;
+    assert_eq!(recv_text(&mut bob).await, "join");
+
+    let close = format!("{}/groups/nokertu/42/close", admin);
+    assert_eq!(
+        admin_post(&close, token, "game over").await,
+        (200, "closed".to_string())
+    );
+    assert_eq!(recv_text(&mut alice).await, "game over");
+    assert_closed(&mut alice).await;
+
+    // without a body, the members get `group closed`
+    let close = format!("{}/groups/nokertu/43/close", admin);
+    assert_eq!(admin_post(&close, token, "").await.0, 200);
+    assert_eq!(recv_text(&mut bob).await, "group closed");
+    assert_closed(&mut bob).await;
+}
+
+#[tokio::test]
+async fn tls_connections_over_the_limit_are_refused_after_the_handshake() {
+    let backend = MockBackend::start().await;
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("chaline-e2e-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.signing_key.serialize_pem()).unwrap();
+
+    let config = GlobalConfig {
+        max_connections_per_ip: Some(1),
+        ..Default::default()
+    };
+    let listener =
+        ListenerConfig::tls("127.0.0.1:0", cert.to_str().unwrap(), key.to_str().unwrap());
+    let server = room_configs(&backend)
+        .into_values()
+        .fold(ServerBuilder::new().config(config), |builder, conf| {
+            builder.room(conf)
+        })
+        .listener(listener)
+        .start()
+        .await
+        .unwrap();
+    let addr = server.local_addrs()[0];
+
+    // the only place is taken by a socket still in its handshake
+    let _held = TcpStream::connect(addr).await.unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let client = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let mut tls = TlsConnector::from(Arc::new(client))
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap();
+    let mut answer = String::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), tls.read_to_string(&mut answer));
+    assert!(read.await.is_ok());
+    assert!(answer.starts_with("HTTP/1.1 429"), "{}", answer);
+    let _ = std::fs::remove_dir_all(&dir);
+}
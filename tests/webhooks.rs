@@ -0,0 +1,116 @@
+mod common;
+
+use std::time::Duration;
+
+use chaline_websocket::config_loader::{WebhookConfig, WebhookEvent};
+use common::{
+    assert_closed, connect, recv_text, room_configs, send, start_server, MockBackend, WebhookStub,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+fn webhook(stub: &WebhookStub, events: &[WebhookEvent]) -> Option<WebhookConfig> {
+    Some(WebhookConfig {
+        url: stub.url.clone(),
+        secret: Some("webhook secret".to_string()),
+        events: events.to_vec(),
+    })
+}
+
+fn signature(body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"webhook secret").unwrap();
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[tokio::test]
+async fn events_are_posted_in_signed_batches() {
+    let backend = MockBackend::start().await;
+    let stub = WebhookStub::start().await;
+    let mut configs = room_configs(&backend);
+    configs.get_mut("micasend").unwrap().webhook = webhook(&stub, &WebhookEvent::ALL);
+    let (url, _server) = start_server(configs).await;
+
+    let mut alice = connect(&url).await;
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+    stub.events(2).await;
+    alice.close(None).await.unwrap();
+    stub.events(3).await;
+
+    // the join and the message are sent together, then the leave
+    let posts = stub.posts();
+    let bodies = posts
+        .iter()
+        .map(|post| serde_json::from_str::<serde_json::Value>(&post.body).unwrap())
+        .collect::<Vec<_>>();
+    let client = &bodies[0]["events"][0]["client"];
+    assert!(client.is_u64());
+    let batches = bodies
+        .iter()
+        .map(|body| {
+            assert_eq!(body["room"], "micasend");
+            let events = body["events"].as_array().unwrap();
+            events
+                .iter()
+                .map(|e| {
+                    assert_eq!(e["roomGroup"], "micasend");
+                    assert_eq!(&e["client"], client);
+                    match e["text"].as_str() {
+                        Some(txt) => format!("{} {}", e["event"].as_str().unwrap(), txt),
+                        None => e["event"].as_str().unwrap().to_string(),
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(batches, vec![vec!["join", "message pong"], vec!["leave"]]);
+    for post in posts {
+        assert_eq!(post.signature, Some(signature(&post.body)));
+    }
+}
+
+#[tokio::test]
+async fn failed_posts_are_retried() {
+    let backend = MockBackend::start().await;
+    let stub = WebhookStub::start().await;
+    stub.fail(2);
+    let mut configs = room_configs(&backend);
+    configs.get_mut("micasend").unwrap().webhook = webhook(&stub, &[WebhookEvent::Message]);
+    let (url, _server) = start_server(configs).await;
+
+    let mut alice = connect(&url).await;
+    send(&mut alice, "micasend:ping").await;
+    assert_eq!(recv_text(&mut alice).await, "pong");
+
+    stub.events(3).await;
+    let posts = stub.posts();
+    assert_eq!(posts.len(), 3);
+    assert!(posts.iter().all(|post| post.body == posts[0].body));
+}
+
+#[tokio::test]
+async fn only_the_configured_events_are_posted() {
+    let backend = MockBackend::start().await;
+    backend.add_group("42");
+    let stub = WebhookStub::start().await;
+    let mut configs = room_configs(&backend);
+    let nokertu = configs.get_mut("nokertu").unwrap();
+    nokertu.webhook = webhook(&stub, &[WebhookEvent::Destroy]);
+    nokertu.anonymous_destroy = true;
+    let (url, _server) = start_server(configs).await;
+
+    let mut alice = connect(&url).await;
+    send(&mut alice, "nokertu/42:join").await;
+    assert_eq!(recv_text(&mut alice).await, "join");
+    backend.remove_group("42");
+    send(&mut alice, "-nokertu/42").await;
+    assert_closed(&mut alice).await;
+
+    let events = stub.events(1).await;
+    assert_eq!(events[0]["event"], "destroy");
+    assert_eq!(events[0]["roomGroup"], "nokertu/42");
+    // the leave of the disconnected member is not posted
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(stub.events(1).await.len(), 1);
+}